@@ -0,0 +1,241 @@
+//! Archive of received offers, one gzip compressed JSONL file per day (UTC)
+//!
+//! Every offer is compressed as a separate gzip member, so a file stays readable after a crash or
+//! a restart. Offers are mostly incompressible anyway, sharing a dictionary wouldn't gain much.
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use splash::ReceivedOffer;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// An archived offer, the hook body along with where and when the offer was received
+#[derive(Serialize, Deserialize)]
+pub struct ArchivedOffer {
+    #[serde(flatten)]
+    pub offer: serde_json::Value,
+    /// Unix time in milliseconds
+    pub received_at: u64,
+    pub message_id: String,
+    pub source: String,
+}
+
+impl ArchivedOffer {
+    pub fn new(received: &ReceivedOffer) -> Self {
+        ArchivedOffer {
+            offer: crate::utils::offer_json(received),
+            received_at: received
+                .received_at
+                .duration_since(UNIX_EPOCH)
+                .map(|received_at| received_at.as_millis() as u64)
+                .unwrap_or_default(),
+            message_id: received.message_id.to_string(),
+            source: received.propagation_source.to_string(),
+        }
+    }
+
+    pub fn received_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.received_at)
+    }
+}
+
+pub struct Archive {
+    dir: PathBuf,
+    // Day of the open file, in days since the unix epoch
+    day: u64,
+    file: Option<File>,
+}
+
+impl Archive {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Archive {
+            dir,
+            day: 0,
+            file: None,
+        })
+    }
+
+    /// Appends an offer to the file of the current day
+    pub fn append(&mut self, offer: &ArchivedOffer) -> io::Result<()> {
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / SECONDS_PER_DAY;
+
+        if self.file.is_none() || self.day != day {
+            let path = self.dir.join(format!("splash-{}.jsonl.gz", date(day)));
+            self.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+            self.day = day;
+        }
+
+        let mut line = serde_json::to_vec(offer)?;
+        line.push(b'\n');
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&line)?;
+        let member = encoder.finish()?;
+
+        self.file
+            .as_mut()
+            .expect("archive file is open")
+            .write_all(&member)
+    }
+}
+
+/// Reads the offers of an archive file, skipping lines that can't be parsed. A file cut off by a
+/// crash ends with an error after its last complete offer.
+pub fn read(path: &Path) -> io::Result<impl Iterator<Item = io::Result<ArchivedOffer>>> {
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+
+    Ok(reader.lines().filter_map(|line| match line {
+        Ok(line) => serde_json::from_str(&line).ok().map(Ok),
+        Err(e) => Some(Err(e)),
+    }))
+}
+
+/// Formats days since the unix epoch as `YYYY-MM-DD`
+fn date(days: u64) -> String {
+    // Howard Hinnant's civil_from_days, with eras starting on March 1st
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// An empty directory for a test, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "splash-archive-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn offer(n: u64) -> ArchivedOffer {
+        ArchivedOffer {
+            offer: json!({ "offer": format!("offer{}", n) }),
+            received_at: 1_700_000_000_000 + n,
+            message_id: format!("message{}", n),
+            source: format!("peer{}", n),
+        }
+    }
+
+    fn files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(59), "1970-03-01");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(11_017), "2000-03-01");
+        assert_eq!(date(19_722), "2023-12-31");
+        assert_eq!(date(19_723), "2024-01-01");
+    }
+
+    #[test]
+    fn reads_appended_offers() {
+        let dir = TestDir::new("append");
+        let mut archive = Archive::new(&dir.0).unwrap();
+        for n in 0..3 {
+            archive.append(&offer(n)).unwrap();
+        }
+        // Reopening the archive keeps appending to the file of the day
+        let mut archive = Archive::new(&dir.0).unwrap();
+        archive.append(&offer(3)).unwrap();
+
+        let files = files(&dir.0);
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("splash-") && name.ends_with(".jsonl.gz"));
+
+        let offers: Vec<ArchivedOffer> =
+            read(&files[0]).unwrap().collect::<io::Result<_>>().unwrap();
+        assert_eq!(offers.len(), 4);
+        for (n, archived) in offers.iter().enumerate() {
+            let expected = offer(n as u64);
+            assert_eq!(archived.offer, expected.offer);
+            assert_eq!(archived.message_id, expected.message_id);
+            assert_eq!(archived.source, expected.source);
+            assert_eq!(
+                archived.received_at(),
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_000 + n as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn skips_lines_that_cant_be_parsed() {
+        let dir = TestDir::new("invalid");
+        fs::create_dir_all(&dir.0).unwrap();
+        let path = dir.0.join("splash-1970-01-01.jsonl.gz");
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        writeln!(encoder, "{}", serde_json::to_string(&offer(0)).unwrap()).unwrap();
+        writeln!(encoder, "not json").unwrap();
+        writeln!(encoder, "{}", serde_json::to_string(&offer(1)).unwrap()).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let offers: Vec<ArchivedOffer> = read(&path).unwrap().collect::<io::Result<_>>().unwrap();
+        assert_eq!(offers.len(), 2);
+        assert_eq!(offers[1].message_id, "message1");
+    }
+
+    #[test]
+    fn reads_offers_before_a_cut_off_end() {
+        let dir = TestDir::new("cut-off");
+        let mut archive = Archive::new(&dir.0).unwrap();
+        archive.append(&offer(0)).unwrap();
+        archive.append(&offer(1)).unwrap();
+        drop(archive);
+
+        let path = files(&dir.0).remove(0);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.truncate(bytes.len() - 10);
+        fs::write(&path, bytes).unwrap();
+
+        let mut offers = read(&path).unwrap();
+        assert_eq!(offers.next().unwrap().unwrap().message_id, "message0");
+        assert!(offers.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Assets traded in an offer, found by uncurrying the puzzles of its coin spends
+//!
+//! Offered CATs are the maker's CAT coins, requested assets are the settlement payments the
+//! offer asks for. Offered XCH is paid into the settlement puzzle by a standard coin spend.
+
+use crate::conditions::CREATE_COIN;
+use crate::{Offer, SplashError};
+use clvm_utils::{tree_hash, TreeHash};
+use clvmr::serde::node_from_bytes;
+use clvmr::{Allocator, Atom, NodePtr, SExp};
+use hex_literal::hex;
+use std::fmt;
+use std::str::FromStr;
+
+const CAT_V2_HASH: [u8; 32] =
+    hex!("37bef360ee858133b69d595a906dc45d01af50379dad515eb9518abb7c1d2a7a");
+const SETTLEMENT_PAYMENTS_HASH: [u8; 32] =
+    hex!("cfbfdeed5c4ca2de3d0bf520b9cb4bb7743a359bd2e6a188d19ce7dffc21d3e7");
+const SETTLEMENT_PAYMENTS_V1_HASH: [u8; 32] =
+    hex!("bae24162efbd568f89bc7a340798a6118df0189eb9e3f8697bcea27af99f8f79");
+
+/// An asset offered or requested by an offer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Asset {
+    Xch,
+    /// A CAT identified by its asset ID, the hash of its TAIL
+    Cat([u8; 32]),
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Asset::Xch => write!(f, "xch"),
+            Asset::Cat(asset_id) => write!(f, "{}", hex::encode(asset_id)),
+        }
+    }
+}
+
+/// Parses `xch` or the hex encoded asset ID of a CAT
+impl FromStr for Asset {
+    type Err = SplashError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("xch") {
+            return Ok(Asset::Xch);
+        }
+
+        let mut asset_id = [0; 32];
+        hex::decode_to_slice(value.trim_start_matches("0x"), &mut asset_id)
+            .map_err(|_| SplashError::InvalidAsset(value.to_string()))?;
+        Ok(Asset::Cat(asset_id))
+    }
+}
+
+/// The assets of an offer, sorted and without duplicates
+pub(crate) fn offer_assets(offer: &Offer) -> Vec<Asset> {
+    let mut assets = Vec::new();
+    let conditions = offer.conditions();
+
+    for (index, coin_spend) in offer.spend_bundle().coin_spends.iter().enumerate() {
+        let mut a = Allocator::new();
+        let Ok(puzzle) = node_from_bytes(&mut a, &coin_spend.puzzle_reveal) else {
+            continue;
+        };
+
+        if let Some(asset_id) = cat_asset_id(&a, puzzle) {
+            assets.push(Asset::Cat(asset_id));
+            continue;
+        }
+
+        let is_settlement = [SETTLEMENT_PAYMENTS_HASH, SETTLEMENT_PAYMENTS_V1_HASH]
+            .contains(&coin_spend.coin.puzzle_hash);
+
+        // Requested XCH is a settlement payment, offered XCH a coin created for the settlement
+        let pays_settlement = conditions
+            .and_then(|conditions| conditions.get(index))
+            .into_iter()
+            .flatten()
+            .filter(|condition| condition.opcode == CREATE_COIN)
+            .any(|condition| {
+                condition.args.first().is_some_and(|puzzle_hash| {
+                    puzzle_hash.as_slice() == SETTLEMENT_PAYMENTS_HASH
+                        || puzzle_hash.as_slice() == SETTLEMENT_PAYMENTS_V1_HASH
+                })
+            });
+
+        if is_settlement || pays_settlement {
+            assets.push(Asset::Xch);
+        }
+    }
+
+    assets.sort();
+    assets.dedup();
+    assets
+}
+
+/// The asset ID curried into a CAT v2 puzzle
+fn cat_asset_id(a: &Allocator, puzzle: NodePtr) -> Option<[u8; 32]> {
+    let (module, args) = uncurry(a, puzzle)?;
+    if tree_hash(a, module) != TreeHash::new(CAT_V2_HASH) {
+        return None;
+    }
+
+    // The CAT puzzle is curried with its own hash, the TAIL hash and the inner puzzle
+    let tail_hash = atom(a, *args.get(1)?)?;
+    tail_hash.as_ref().try_into().ok()
+}
+
+/// Splits `(a (q . module) (c (q . arg1) (c (q . arg2) ... 1)))` into the module and its
+/// arguments
+fn uncurry(a: &Allocator, program: NodePtr) -> Option<(NodePtr, Vec<NodePtr>)> {
+    let [apply, quoted_module, mut env] = list::<3>(a, program)?;
+    if atom(a, apply)?.as_ref() != [2] {
+        return None;
+    }
+    let module = unquote(a, quoted_module)?;
+
+    let mut args = Vec::new();
+    while atom(a, env).is_none_or(|env| env.as_ref() != [1]) {
+        let [cons, quoted_arg, rest] = list::<3>(a, env)?;
+        if atom(a, cons)?.as_ref() != [4] {
+            return None;
+        }
+        args.push(unquote(a, quoted_arg)?);
+        env = rest;
+    }
+
+    Some((module, args))
+}
+
+fn unquote(a: &Allocator, node: NodePtr) -> Option<NodePtr> {
+    match a.sexp(node) {
+        SExp::Pair(quote, value) if atom(a, quote)?.as_ref() == [1] => Some(value),
+        _ => None,
+    }
+}
+
+/// The items of a proper list of exactly `N` items
+fn list<const N: usize>(a: &Allocator, node: NodePtr) -> Option<[NodePtr; N]> {
+    let mut items = [a.nil(); N];
+    let mut node = node;
+
+    for item in items.iter_mut() {
+        let SExp::Pair(first, rest) = a.sexp(node) else {
+            return None;
+        };
+        *item = first;
+        node = rest;
+    }
+
+    (atom(a, node)?.is_empty()).then_some(items)
+}
+
+fn atom(a: &Allocator, node: NodePtr) -> Option<Atom<'_>> {
+    match a.sexp(node) {
+        SExp::Atom => Some(a.atom(node)),
+        SExp::Pair(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    /// Curries `args` into `module` the way `uncurry` expects
+    fn curry(a: &mut Allocator, module: NodePtr, args: &[NodePtr]) -> NodePtr {
+        let quote = a.new_atom(&[1]).unwrap();
+        let cons = a.new_atom(&[4]).unwrap();
+        let apply = a.new_atom(&[2]).unwrap();
+
+        let mut env = quote;
+        for &arg in args.iter().rev() {
+            let quoted_arg = a.new_pair(quote, arg).unwrap();
+            env = new_list(a, &[cons, quoted_arg, env]);
+        }
+        let quoted_module = a.new_pair(quote, module).unwrap();
+        new_list(a, &[apply, quoted_module, env])
+    }
+
+    fn new_list(a: &mut Allocator, items: &[NodePtr]) -> NodePtr {
+        items
+            .iter()
+            .rev()
+            .fold(a.nil(), |rest, &item| a.new_pair(item, rest).unwrap())
+    }
+
+    #[test]
+    fn parses_assets() {
+        let asset_id = hex!("7cc7e6a7c9fb65550e8fda00764038cf706d403d8c0cf59da7dfee778f3841d1");
+        let encoded = "7cc7e6a7c9fb65550e8fda00764038cf706d403d8c0cf59da7dfee778f3841d1";
+
+        assert_eq!("xch".parse::<Asset>().unwrap(), Asset::Xch);
+        assert_eq!("XCH".parse::<Asset>().unwrap(), Asset::Xch);
+        assert_eq!(encoded.parse::<Asset>().unwrap(), Asset::Cat(asset_id));
+        assert_eq!(
+            format!("0x{}", encoded).parse::<Asset>().unwrap(),
+            Asset::Cat(asset_id)
+        );
+
+        assert_eq!(Asset::Xch.to_string(), "xch");
+        assert_eq!(Asset::Cat(asset_id).to_string(), encoded);
+    }
+
+    #[test]
+    fn rejects_invalid_assets() {
+        for value in ["", "btc", "7cc7e6a7", &"zz".repeat(32), &"00".repeat(33)] {
+            assert!(matches!(
+                value.parse::<Asset>(),
+                Err(SplashError::InvalidAsset(invalid)) if invalid == value
+            ));
+        }
+    }
+
+    #[test]
+    fn uncurries_programs() {
+        let mut a = Allocator::new();
+        let module = a.new_atom(b"module").unwrap();
+        let args = [
+            a.new_atom(b"first").unwrap(),
+            a.new_atom(b"second").unwrap(),
+        ];
+        let curried = curry(&mut a, module, &args);
+
+        let (uncurried, uncurried_args) = uncurry(&a, curried).unwrap();
+        assert_eq!(uncurried, module);
+        assert_eq!(uncurried_args, args);
+
+        let curried = curry(&mut a, module, &[]);
+        assert_eq!(uncurry(&a, curried), Some((module, Vec::new())));
+    }
+
+    #[test]
+    fn doesnt_uncurry_other_programs() {
+        let mut a = Allocator::new();
+        let module = a.new_atom(b"module").unwrap();
+        let quote = a.new_atom(&[1]).unwrap();
+        let quoted_module = a.new_pair(quote, module).unwrap();
+
+        // Not an application
+        let run = a.new_atom(&[3]).unwrap();
+        let program = new_list(&mut a, &[run, quoted_module, quote]);
+        assert_eq!(uncurry(&a, program), None);
+
+        // The environment isn't built of quoted arguments
+        let apply = a.new_atom(&[2]).unwrap();
+        let program = new_list(&mut a, &[apply, quoted_module, module]);
+        assert_eq!(uncurry(&a, program), None);
+
+        // Too many items
+        let program = new_list(&mut a, &[apply, quoted_module, quote, quote]);
+        assert_eq!(uncurry(&a, program), None);
+
+        assert_eq!(uncurry(&a, module), None);
+    }
+
+    #[test]
+    fn finds_asset_ids_of_cats() {
+        let offer = Offer::decode(fixtures::CAT_FOR_XCH).unwrap();
+        let asset_ids: Vec<Option<[u8; 32]>> = offer
+            .spend_bundle()
+            .coin_spends
+            .iter()
+            .map(|coin_spend| {
+                let mut a = Allocator::new();
+                let puzzle = node_from_bytes(&mut a, &coin_spend.puzzle_reveal).unwrap();
+                cat_asset_id(&a, puzzle)
+            })
+            .collect();
+
+        // The offered CAT coin, the requested XCH is a settlement payment
+        assert!(asset_ids.contains(&Some(hex!(
+            "4c278e415c32758545a893ad89d9e2a772b7dcd07a633b72befc0545297d3444"
+        ))));
+        assert!(asset_ids.contains(&None));
+
+        // Curried programs of other modules aren't CATs
+        let mut a = Allocator::new();
+        let module = a.new_atom(b"module").unwrap();
+        let tail_hash = a.new_atom(&[0; 32]).unwrap();
+        let curried = curry(&mut a, module, &[module, tail_hash, module]);
+        assert_eq!(cat_asset_id(&a, curried), None);
+    }
+}
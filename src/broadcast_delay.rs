@@ -0,0 +1,109 @@
+//! Randomized delay of the offers we broadcast, so their timing doesn't give away they are ours
+//!
+//! Every offer is held for a random time of up to the maximum delay. Once that passed, it goes out
+//! along with the next offer we relay for a peer, or when the maximum delay is up if we don't relay
+//! any. Our offers then leave at the same moments as offers of others.
+
+use rand::Rng;
+use tokio::time::{self, Duration, Instant};
+
+struct Held<T> {
+    ready_at: Instant,
+    deadline: Instant,
+    item: T,
+}
+
+pub(crate) struct BroadcastDelay<T> {
+    max_delay: Duration,
+    held: Vec<Held<T>>,
+}
+
+impl<T> BroadcastDelay<T> {
+    pub fn new(max_delay: Duration) -> Self {
+        BroadcastDelay {
+            max_delay,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn hold(&mut self, item: T) {
+        let now = Instant::now();
+        let delay = self
+            .max_delay
+            .mul_f64(rand::thread_rng().gen_range(0.0..1.0));
+
+        self.held.push(Held {
+            ready_at: now + delay,
+            deadline: now + self.max_delay,
+            item,
+        });
+    }
+
+    /// Takes the items whose delay passed, to send them along with relayed traffic
+    pub fn ready(&mut self) -> Vec<T> {
+        self.take(|held| held.ready_at <= Instant::now())
+    }
+
+    /// Waits for the maximum delay of an item to be up, returns all items due by then
+    pub async fn overdue(&mut self) -> Vec<T> {
+        let Some(deadline) = self.held.iter().map(|held| held.deadline).min() else {
+            return futures::future::pending().await;
+        };
+
+        time::sleep_until(deadline).await;
+
+        self.take(|held| held.deadline <= Instant::now())
+    }
+
+    fn take(&mut self, due: impl Fn(&Held<T>) -> bool) -> Vec<T> {
+        let (due, held) = self.held.drain(..).partition(due);
+        self.held = held;
+        due.into_iter().map(|held: Held<T>| held.item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_items_until_their_delay_passed() {
+        let mut delay = BroadcastDelay::new(MAX_DELAY);
+        delay.hold(1);
+        delay.hold(2);
+
+        assert!(delay.ready().is_empty());
+
+        time::advance(MAX_DELAY).await;
+        let mut ready = delay.ready();
+        ready.sort();
+        assert_eq!(ready, vec![1, 2]);
+        assert!(delay.ready().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_items_at_their_deadline() {
+        let mut delay = BroadcastDelay::new(MAX_DELAY);
+        delay.hold(1);
+        time::advance(MAX_DELAY / 2).await;
+        delay.hold(2);
+
+        let start = Instant::now();
+        assert_eq!(delay.overdue().await, vec![1]);
+        assert_eq!(start.elapsed(), MAX_DELAY / 2);
+
+        assert_eq!(delay.overdue().await, vec![2]);
+        assert_eq!(start.elapsed(), MAX_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_without_items() {
+        let mut delay = BroadcastDelay::<u8>::new(MAX_DELAY);
+
+        assert!(time::timeout(MAX_DELAY * 10, delay.overdue())
+            .await
+            .is_err());
+    }
+}
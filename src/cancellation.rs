@@ -0,0 +1,70 @@
+//! Signed announcements that an offer was cancelled
+//!
+//! A cancellation carries the compressed offer, a BLS public key and a signature of
+//! [`Cancellation::message`] with that key. It's valid if the public key is required to sign for
+//! one of the coins the offer spends, which only the maker can do. Payloads use the Chia
+//! streamable format: the offer (`u32` length followed by the bytes), the public key (48 bytes)
+//! and the signature (96 bytes).
+
+use crate::offer::Reader;
+use crate::{OfferId, SplashError};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+const CANCELLATION_DOMAIN: &[u8] = b"splash offer cancellation";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancellation {
+    /// Compressed bytes of the cancelled offer
+    pub offer: Bytes,
+    pub public_key: [u8; 48],
+    pub signature: [u8; 96],
+}
+
+impl Cancellation {
+    /// The message signed to cancel `offer_id`, bound to the network by its genesis challenge
+    pub fn message(offer_id: &OfferId, genesis_challenge: &[u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(CANCELLATION_DOMAIN)
+            .chain_update(genesis_challenge)
+            .chain_update(offer_id.0)
+            .finalize()
+            .into()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.offer.len() + 48 + 96);
+        bytes.extend_from_slice(&(self.offer.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.offer);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Parses a cancellation, the offer shares the memory of `bytes`
+    pub fn from_bytes(bytes: Bytes) -> Result<Cancellation, SplashError> {
+        let mut reader = Reader::new(&bytes);
+
+        let mut read = || -> Result<_, String> {
+            let offer_len = reader.bytes()?.len();
+            let public_key = reader.array()?;
+            let signature = reader.array()?;
+            Ok((offer_len, public_key, signature))
+        };
+        let (offer_len, public_key, signature) =
+            read().map_err(SplashError::InvalidCancellation)?;
+
+        if !reader.is_empty() {
+            return Err(SplashError::InvalidCancellation(format!(
+                "{} trailing bytes",
+                reader.remaining()
+            )));
+        }
+
+        Ok(Cancellation {
+            offer: bytes.slice(4..4 + offer_len),
+            public_key,
+            signature,
+        })
+    }
+}
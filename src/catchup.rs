@@ -0,0 +1,414 @@
+//! Catch-up on offers published while a node was offline
+//!
+//! Nodes keep the offers they received recently and hand them out over a request-response
+//! protocol. Requests contain the maximum age of the offers in seconds (`u32`), responses a list
+//! (`u32` count) of message envelopes (`u32` length followed by the bytes), newest first.
+
+use crate::envelope::{Envelope, MessageType};
+use crate::offer::Reader;
+use crate::OfferId;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// Offers are kept for at most this long
+pub(crate) const MAX_CATCHUP_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+// A few thousand average offers, well below the size that would stall a connection
+const MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct CatchupRequest {
+    pub max_age: Duration,
+}
+
+#[derive(Debug)]
+pub(crate) struct CatchupResponse {
+    pub messages: Vec<Bytes>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CatchupCodec;
+
+#[async_trait]
+impl request_response::Codec for CatchupCodec {
+    type Protocol = StreamProtocol;
+    type Request = CatchupRequest;
+    type Response = CatchupResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut seconds = [0; 4];
+        io.read_exact(&mut seconds).await?;
+
+        Ok(CatchupRequest {
+            max_age: Duration::from_secs(u32::from_be_bytes(seconds) as u64),
+        })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_response(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let seconds = request.max_age.as_secs().min(u32::MAX as u64) as u32;
+        io.write_all(&seconds.to_be_bytes()).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_response(io, response).await
+    }
+}
+
+/// Reads a list of envelopes, also used by the sync protocol
+pub(crate) async fn read_response<T>(io: &mut T) -> io::Result<CatchupResponse>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut data = Vec::new();
+    io.take(MAX_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut data)
+        .await?;
+
+    if data.len() > MAX_RESPONSE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "catch-up response too large",
+        ));
+    }
+
+    let data = Bytes::from(data);
+    let mut reader = Reader::new(&data);
+
+    let mut read = || -> Result<_, String> {
+        let count = reader.u32()?;
+        let mut messages = Vec::new();
+        for _ in 0..count {
+            let message = reader.bytes()?;
+            let end = data.len() - reader.remaining();
+            messages.push(data.slice(end - message.len()..end));
+        }
+        Ok(messages)
+    };
+
+    let messages = read().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(CatchupResponse { messages })
+}
+
+pub(crate) async fn write_response<T>(io: &mut T, response: CatchupResponse) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let mut data = Vec::new();
+    data.extend_from_slice(&(response.messages.len() as u32).to_be_bytes());
+    for message in response.messages {
+        data.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        data.extend_from_slice(&message);
+    }
+
+    io.write_all(&data).await
+}
+
+struct RecentOffer {
+    received_at: SystemTime,
+    offer_id: OfferId,
+    envelope: Bytes,
+}
+
+/// Offers received within the last [`MAX_CATCHUP_WINDOW`], at most `capacity` of them
+pub(crate) struct RecentOffers {
+    // Oldest first, along with the time they were received
+    offers: VecDeque<RecentOffer>,
+    capacity: usize,
+}
+
+impl RecentOffers {
+    pub fn new(capacity: usize) -> Self {
+        RecentOffers {
+            offers: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Keeps an offer, `published_at` is passed on to peers catching up
+    pub fn insert(
+        &mut self,
+        offer_id: OfferId,
+        compressed: Bytes,
+        received_at: SystemTime,
+        published_at: Option<SystemTime>,
+    ) {
+        let envelope = Envelope {
+            message_type: MessageType::BinaryOffer,
+            flags: 0,
+            timestamp: published_at.or(Some(received_at)),
+            payload: compressed,
+        };
+
+        self.offers.push_back(RecentOffer {
+            received_at,
+            offer_id,
+            envelope: envelope.to_bytes().into(),
+        });
+
+        while self.offers.len() > self.capacity {
+            self.offers.pop_front();
+        }
+
+        while self.offers.front().is_some_and(|offer| {
+            offer.received_at.elapsed().unwrap_or_default() > MAX_CATCHUP_WINDOW
+        }) {
+            self.offers.pop_front();
+        }
+    }
+
+    /// Forgets a taken or cancelled offer
+    pub fn remove(&mut self, offer_id: &OfferId) {
+        self.offers.retain(|offer| offer.offer_id != *offer_id);
+    }
+
+    pub fn offer_ids(&self) -> impl Iterator<Item = &OfferId> {
+        self.offers.iter().map(|offer| &offer.offer_id)
+    }
+
+    /// The offers received within `max_age`, newest first
+    pub fn response(&self, max_age: Duration) -> CatchupResponse {
+        response(
+            self.offers
+                .iter()
+                .rev()
+                .take_while(|offer| offer.received_at.elapsed().unwrap_or_default() <= max_age),
+        )
+    }
+
+    /// The offers whose IDs `known` doesn't contain, newest first
+    pub fn missing(&self, known: impl Fn(&OfferId) -> bool) -> CatchupResponse {
+        response(
+            self.offers
+                .iter()
+                .rev()
+                .filter(|offer| !known(&offer.offer_id)),
+        )
+    }
+}
+
+/// Envelopes of `offers` up to the response size limit
+fn response<'a>(offers: impl Iterator<Item = &'a RecentOffer>) -> CatchupResponse {
+    let mut size = 4;
+
+    let messages = offers
+        .map(|offer| &offer.envelope)
+        .take_while(|message| {
+            size += 4 + message.len();
+            size <= MAX_RESPONSE_SIZE
+        })
+        .cloned()
+        .collect();
+
+    CatchupResponse { messages }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    const PROTOCOL: StreamProtocol = StreamProtocol::new("/splash/catchup/1");
+
+    fn offer_id(id: u8) -> OfferId {
+        OfferId([id; 32])
+    }
+
+    // Envelopes keep whole seconds
+    fn seconds_ago(seconds: u64) -> SystemTime {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        SystemTime::UNIX_EPOCH + Duration::from_secs(now.as_secs() - seconds)
+    }
+
+    fn payloads(response: CatchupResponse) -> Vec<Bytes> {
+        response
+            .messages
+            .into_iter()
+            .map(|message| Envelope::from_bytes(message).unwrap().payload)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn round_trips_requests() {
+        for (max_age, expected) in [
+            (Duration::from_secs(600), Duration::from_secs(600)),
+            (Duration::MAX, Duration::from_secs(u32::MAX as u64)),
+        ] {
+            let mut io = Cursor::new(Vec::new());
+            CatchupCodec
+                .write_request(&PROTOCOL, &mut io, CatchupRequest { max_age })
+                .await
+                .unwrap();
+
+            io.set_position(0);
+            let request = CatchupCodec.read_request(&PROTOCOL, &mut io).await.unwrap();
+            assert_eq!(request.max_age, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_responses() {
+        let messages = vec![Bytes::from_static(b"first"), Bytes::new()];
+
+        let mut io = Cursor::new(Vec::new());
+        write_response(
+            &mut io,
+            CatchupResponse {
+                messages: messages.clone(),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(io.get_ref()[..4], [0, 0, 0, 2]);
+
+        io.set_position(0);
+        assert_eq!(read_response(&mut io).await.unwrap().messages, messages);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_responses() {
+        // Claims a second message
+        let mut io = Cursor::new(b"\x00\x00\x00\x02\x00\x00\x00\x01a".to_vec());
+        assert!(read_response(&mut io).await.is_err());
+
+        let mut io = Cursor::new(vec![0; MAX_RESPONSE_SIZE + 1]);
+        assert!(read_response(&mut io).await.is_err());
+    }
+
+    #[test]
+    fn hands_out_recent_offers_newest_first() {
+        let mut offers = RecentOffers::new(10);
+        let now = SystemTime::now();
+        offers.insert(
+            offer_id(1),
+            Bytes::from_static(b"old"),
+            now - Duration::from_secs(120),
+            None,
+        );
+        offers.insert(offer_id(2), Bytes::from_static(b"new"), now, None);
+
+        assert_eq!(
+            payloads(offers.response(MAX_CATCHUP_WINDOW)),
+            vec![Bytes::from_static(b"new"), Bytes::from_static(b"old")]
+        );
+        assert_eq!(
+            payloads(offers.response(Duration::from_secs(60))),
+            vec![Bytes::from_static(b"new")]
+        );
+    }
+
+    #[test]
+    fn timestamps_offers_with_their_publish_time() {
+        let mut offers = RecentOffers::new(10);
+        let received_at = seconds_ago(0);
+        let published_at = seconds_ago(100);
+        offers.insert(offer_id(1), Bytes::new(), received_at, Some(published_at));
+        offers.insert(offer_id(2), Bytes::new(), received_at, None);
+
+        let timestamps: Vec<_> = offers
+            .missing(|_| false)
+            .messages
+            .into_iter()
+            .map(|message| Envelope::from_bytes(message).unwrap().timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![Some(received_at), Some(published_at)]);
+    }
+
+    #[test]
+    fn keeps_the_newest_offers() {
+        let mut offers = RecentOffers::new(2);
+        let now = SystemTime::now();
+        offers.insert(
+            offer_id(1),
+            Bytes::new(),
+            now - MAX_CATCHUP_WINDOW - Duration::from_secs(1),
+            None,
+        );
+        offers.insert(offer_id(2), Bytes::new(), now, None);
+        // Expired offers are dropped as new ones come in
+        assert_eq!(offers.offer_ids().collect::<Vec<_>>(), vec![&offer_id(2)]);
+
+        offers.insert(offer_id(3), Bytes::new(), now, None);
+        offers.insert(offer_id(4), Bytes::new(), now, None);
+        assert_eq!(
+            offers.offer_ids().collect::<Vec<_>>(),
+            vec![&offer_id(3), &offer_id(4)]
+        );
+
+        offers.remove(&offer_id(3));
+        assert_eq!(offers.offer_ids().collect::<Vec<_>>(), vec![&offer_id(4)]);
+    }
+
+    #[test]
+    fn hands_out_missing_offers() {
+        let mut offers = RecentOffers::new(10);
+        for id in 1..=3 {
+            offers.insert(offer_id(id), Bytes::from(vec![id]), SystemTime::now(), None);
+        }
+
+        assert_eq!(
+            payloads(offers.missing(|offer_id| *offer_id == OfferId([2; 32]))),
+            vec![Bytes::from(vec![3]), Bytes::from(vec![1])]
+        );
+    }
+
+    #[test]
+    fn limits_the_response_size() {
+        let mut offers = RecentOffers::new(10);
+        for id in 1..=3 {
+            offers.insert(
+                offer_id(id),
+                Bytes::from(vec![id; MAX_RESPONSE_SIZE / 3]),
+                SystemTime::now(),
+                None,
+            );
+        }
+
+        // The envelopes of all three exceed the limit
+        assert_eq!(
+            payloads(offers.response(MAX_CATCHUP_WINDOW))
+                .iter()
+                .map(|payload| payload[0])
+                .collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::offer::{Coin, CoinSpend};
+use crate::SplashError;
+use clvm_utils::{tree_hash, TreeHash};
+use clvmr::reduction::Reduction;
+use clvmr::serde::node_from_bytes;
+use clvmr::{run_program, Allocator, ChiaDialect, ClvmFlags, NodePtr, SExp};
+use sha2::{Digest, Sha256};
+
+// Same limit as a full block, no valid offer can exceed it
+const MAX_COST: u64 = 11_000_000_000;
+
+pub const AGG_SIG_PARENT: u16 = 43;
+pub const AGG_SIG_PUZZLE: u16 = 44;
+pub const AGG_SIG_AMOUNT: u16 = 45;
+pub const AGG_SIG_PUZZLE_AMOUNT: u16 = 46;
+pub const AGG_SIG_PARENT_AMOUNT: u16 = 47;
+pub const AGG_SIG_PARENT_PUZZLE: u16 = 48;
+pub const AGG_SIG_UNSAFE: u16 = 49;
+pub const AGG_SIG_ME: u16 = 50;
+pub const CREATE_COIN: u16 = 51;
+pub const ASSERT_BEFORE_SECONDS_RELATIVE: u16 = 84;
+pub const ASSERT_BEFORE_SECONDS_ABSOLUTE: u16 = 85;
+pub const ASSERT_BEFORE_HEIGHT_RELATIVE: u16 = 86;
+pub const ASSERT_BEFORE_HEIGHT_ABSOLUTE: u16 = 87;
+
+/// A condition output by a puzzle, only the leading atom arguments are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub opcode: u16,
+    pub args: Vec<Vec<u8>>,
+}
+
+impl Condition {
+    /// Argument `index` as an unsigned integer, `None` if it's missing, negative or too large
+    pub fn u64_arg(&self, index: usize) -> Option<u64> {
+        let arg = self.args.get(index)?;
+        if arg.first().is_some_and(|&b| b & 0x80 != 0) {
+            return None;
+        }
+        let arg = &arg[arg.iter().take_while(|&&b| b == 0).count()..];
+        if arg.len() > 8 {
+            return None;
+        }
+        Some(arg.iter().fold(0u64, |n, &b| (n << 8) | b as u64))
+    }
+}
+
+impl Coin {
+    pub fn coin_id(&self) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(self.parent_coin_info)
+            .chain_update(self.puzzle_hash)
+            .chain_update(int_to_bytes(self.amount))
+            .finalize()
+            .into()
+    }
+}
+
+impl CoinSpend {
+    /// Runs the puzzle reveal with the solution and returns the conditions it outputs
+    pub fn conditions(&self) -> Result<Vec<Condition>, SplashError> {
+        let failed = |e: &dyn std::fmt::Display| SplashError::PuzzleFailed(e.to_string());
+
+        let mut a = Allocator::new();
+        let puzzle = node_from_bytes(&mut a, &self.puzzle_reveal).map_err(|e| failed(&e))?;
+        let solution = node_from_bytes(&mut a, &self.solution).map_err(|e| failed(&e))?;
+
+        if tree_hash(&a, puzzle) != TreeHash::new(self.coin.puzzle_hash) {
+            return Err(failed(
+                &"puzzle reveal doesn't match the coin's puzzle hash",
+            ));
+        }
+
+        let dialect = ChiaDialect::new(ClvmFlags::LIMIT_HEAP);
+        let Reduction(_, output) =
+            run_program(&mut a, &dialect, puzzle, solution, MAX_COST).map_err(|e| failed(&e))?;
+
+        parse_conditions(&a, output).map_err(|e| failed(&e))
+    }
+}
+
+fn parse_conditions(a: &Allocator, output: NodePtr) -> Result<Vec<Condition>, &'static str> {
+    let mut conditions = Vec::new();
+
+    for condition in list_iter(a, output) {
+        let SExp::Pair(opcode, args) = a.sexp(condition) else {
+            return Err("condition is not a list");
+        };
+        let SExp::Atom = a.sexp(opcode) else {
+            return Err("condition opcode is a pair");
+        };
+
+        // Opcodes longer than two bytes are unknown conditions, which are allowed
+        let opcode = match a.atom(opcode).as_ref() {
+            [op] => *op as u16,
+            [0, op] if *op & 0x80 != 0 => *op as u16,
+            [high, low] if *high & 0x80 == 0 => u16::from_be_bytes([*high, *low]),
+            _ => continue,
+        };
+
+        let args = list_iter(a, args)
+            .map_while(|arg| matches!(a.sexp(arg), SExp::Atom).then(|| a.atom(arg).to_vec()))
+            .collect();
+
+        conditions.push(Condition { opcode, args });
+    }
+
+    Ok(conditions)
+}
+
+/// The items of a list, stops at the first atom
+fn list_iter(a: &Allocator, mut list: NodePtr) -> impl Iterator<Item = NodePtr> + '_ {
+    std::iter::from_fn(move || match a.sexp(list) {
+        SExp::Pair(first, rest) => {
+            list = rest;
+            Some(first)
+        }
+        SExp::Atom => None,
+    })
+}
+
+/// Minimal signed big endian encoding, the way clvm represents integers
+pub(crate) fn int_to_bytes(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&b| b == 0).count();
+    let mut encoded = bytes[skip..].to_vec();
+    if encoded.first().is_some_and(|&b| b & 0x80 != 0) {
+        encoded.insert(0, 0);
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clvmr::serde::node_to_bytes;
+    use hex_literal::hex;
+
+    const Q: u8 = 1;
+    const C: u8 = 4;
+    const POINT_ADD: u8 = 29;
+    const PUBKEY_FOR_EXP: u8 = 30;
+
+    const G1: [u8; 48] = hex!("97f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac586c55e83ff97a1aeffb3af00adb22c6bb");
+    const G1_TIMES_2: [u8; 48] = hex!("a572cbea904d67468808c8eb50a9450c9721db309128012543902d0ac358a62ae28f75bb8f1c7c42c39a8c5529bf0f4e");
+    const G1_TIMES_3: [u8; 48] = hex!("89ece308f9d1f0131765212deca99697b112d61f9be9a5f1f3780a51335b3ff981747a0b2ca2179b96d2c0c9024e5224");
+
+    fn list(a: &mut Allocator, items: &[NodePtr]) -> NodePtr {
+        items
+            .iter()
+            .rev()
+            .fold(NodePtr::NIL, |rest, &item| a.new_pair(item, rest).unwrap())
+    }
+
+    /// A coin spend whose puzzle outputs `(AGG_SIG_UNSAFE <operator applied to solution> "msg")`
+    fn operator_spend(operator: u8, arity: usize, solution: &[&[u8]]) -> CoinSpend {
+        let mut a = Allocator::new();
+
+        let atom = |a: &mut Allocator, bytes: &[u8]| a.new_atom(bytes).unwrap();
+        let quote = |a: &mut Allocator, node: NodePtr| {
+            let q = atom(a, &[Q]);
+            a.new_pair(q, node).unwrap()
+        };
+        // `(c x rest)` builds the list at runtime
+        let cons = |a: &mut Allocator, first: NodePtr, rest: NodePtr| {
+            let c = atom(a, &[C]);
+            list(a, &[c, first, rest])
+        };
+
+        // Paths to the items of the solution: 2, 5, 11...
+        let mut args = vec![atom(&mut a, &[operator])];
+        let mut path = 2u8;
+        for _ in 0..arity {
+            args.push(atom(&mut a, &[path]));
+            path = path * 2 + 1;
+        }
+        let operation = list(&mut a, &args);
+
+        let opcode = atom(&mut a, &[AGG_SIG_UNSAFE as u8]);
+        let opcode = quote(&mut a, opcode);
+        let message = atom(&mut a, b"msg");
+        let message = list(&mut a, &[message]);
+        let message = quote(&mut a, message);
+        let condition = cons(&mut a, operation, message);
+        let condition = cons(&mut a, opcode, condition);
+        let puzzle = cons(&mut a, condition, NodePtr::NIL);
+
+        let solution: Vec<_> = solution.iter().map(|bytes| atom(&mut a, bytes)).collect();
+        let solution = list(&mut a, &solution);
+
+        CoinSpend {
+            coin: Coin {
+                parent_coin_info: [1; 32],
+                puzzle_hash: tree_hash(&a, puzzle).to_bytes(),
+                amount: 1,
+            },
+            puzzle_reveal: node_to_bytes(&a, puzzle).unwrap(),
+            solution: node_to_bytes(&a, solution).unwrap(),
+        }
+    }
+
+    /// Runs `operator` on `args` and returns its result
+    fn run_operator(operator: u8, args: &[&[u8]]) -> Result<Vec<u8>, SplashError> {
+        let conditions = operator_spend(operator, args.len(), args).conditions()?;
+        assert_eq!(conditions.len(), 1);
+        assert_eq!(conditions[0].opcode, AGG_SIG_UNSAFE);
+        Ok(conditions[0].args[0].clone())
+    }
+
+    #[test]
+    fn pubkey_for_exp_matches_the_chia_vectors() {
+        let vectors: [(&[u8], [u8; 48]); 6] = [
+            (&[1], G1),
+            (&[2], G1_TIMES_2),
+            (&[3], G1_TIMES_3),
+            (&[5], hex!("b0e7791fb972fe014159aa33a98622da3cdc98ff707965e536d8636b5fcc5ac7a91a8c46e59a00dca575af0f18fb13dc")),
+            (&[0xff], hex!("b7f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac586c55e83ff97a1aeffb3af00adb22c6bb")),
+            (&[0xfb], hex!("90e7791fb972fe014159aa33a98622da3cdc98ff707965e536d8636b5fcc5ac7a91a8c46e59a00dca575af0f18fb13dc")),
+        ];
+
+        for (exponent, public_key) in vectors {
+            assert_eq!(
+                run_operator(PUBKEY_FOR_EXP, &[exponent]).unwrap(),
+                public_key
+            );
+        }
+
+        // The group order wraps around to the point at infinity
+        let group_order = hex!("73eda753299d7d483339d80809a1d80553bda402fffe5bfeffffffff00000001");
+        let mut infinity = [0; 48];
+        infinity[0] = 0xc0;
+        assert_eq!(
+            run_operator(PUBKEY_FOR_EXP, &[&group_order]).unwrap(),
+            infinity
+        );
+    }
+
+    #[test]
+    fn point_add_matches_the_chia_vectors() {
+        assert_eq!(
+            run_operator(POINT_ADD, &[&G1, &G1_TIMES_2]).unwrap(),
+            G1_TIMES_3
+        );
+        assert_eq!(run_operator(POINT_ADD, &[&G1_TIMES_3]).unwrap(), G1_TIMES_3);
+
+        let mut infinity = [0; 48];
+        infinity[0] = 0xc0;
+        assert_eq!(run_operator(POINT_ADD, &[]).unwrap(), infinity);
+
+        // Points must be 48 bytes
+        assert!(matches!(
+            run_operator(POINT_ADD, &[&G1[..47]]),
+            Err(SplashError::PuzzleFailed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_puzzle_reveals() {
+        let mut coin_spend = operator_spend(POINT_ADD, 0, &[]);
+        coin_spend.coin.puzzle_hash = [0; 32];
+
+        assert!(matches!(
+            coin_spend.conditions(),
+            Err(SplashError::PuzzleFailed(_))
+        ));
+    }
+
+    #[test]
+    fn encodes_integers_like_clvm() {
+        assert_eq!(int_to_bytes(0), b"");
+        assert_eq!(int_to_bytes(1), [1]);
+        assert_eq!(int_to_bytes(0x7f), [0x7f]);
+        assert_eq!(int_to_bytes(0x80), [0, 0x80]);
+        assert_eq!(int_to_bytes(0x1234), [0x12, 0x34]);
+        assert_eq!(
+            int_to_bytes(u64::MAX),
+            [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn parses_unsigned_arguments() {
+        let condition = Condition {
+            opcode: ASSERT_BEFORE_SECONDS_ABSOLUTE,
+            args: vec![
+                vec![0, 0x80],
+                vec![0x80],
+                vec![],
+                vec![0, 0, 1, 2, 3, 4, 5, 6, 7, 8],
+                vec![1, 2, 3, 4, 5, 6, 7, 8, 9],
+            ],
+        };
+
+        assert_eq!(condition.u64_arg(0), Some(0x80));
+        assert_eq!(condition.u64_arg(1), None);
+        assert_eq!(condition.u64_arg(2), Some(0));
+        assert_eq!(condition.u64_arg(3), Some(0x0102030405060708));
+        assert_eq!(condition.u64_arg(4), None);
+        assert_eq!(condition.u64_arg(5), None);
+    }
+
+    #[test]
+    fn computes_coin_ids() {
+        let coin = Coin {
+            parent_coin_info: hex!(
+                "70788b9d6210d1870cda0f02887c9e282b1baafc8ddc7e1229fccd01bfd9c490"
+            ),
+            puzzle_hash: hex!("f5c0afb9d48d677638ced3f94a5348cf7e8444d455caf6780798c254dd266186"),
+            amount: 100,
+        };
+
+        assert_eq!(
+            coin.coin_id(),
+            hex!("53645c83e898184cb28ecf460ec144e957eba68f4c3d93d39251f7105665aed0")
+        );
+    }
+}
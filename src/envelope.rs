@@ -0,0 +1,284 @@
+//! Versioned binary framing of gossip messages
+//!
+//! Envelopes use the Chia streamable format: the envelope version (`u8`), the message type
+//! (`u8`), flags (`u32`), an optional timestamp in seconds since the unix epoch (`u64`) and the
+//! length prefixed payload. Legacy messages are bare UTF-8 offer strings, they never start with
+//! a valid envelope version.
+
+use crate::offer::Reader;
+use crate::{Cancellation, SplashError, TakenNotice};
+use bytes::Bytes;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// What the payload of an envelope contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    /// An `offer1...` string
+    Offer,
+    /// The compressed bytes of an offer, what an `offer1...` string encodes
+    BinaryOffer,
+    /// A [`crate::Cancellation`]
+    OfferCancellation,
+    /// A [`crate::TakenNotice`]
+    OfferTaken,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<MessageType> {
+        match value {
+            1 => Some(MessageType::Offer),
+            2 => Some(MessageType::BinaryOffer),
+            3 => Some(MessageType::OfferCancellation),
+            4 => Some(MessageType::OfferTaken),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            MessageType::Offer => 1,
+            MessageType::BinaryOffer => 2,
+            MessageType::OfferCancellation => 3,
+            MessageType::OfferTaken => 4,
+        }
+    }
+}
+
+/// How offers are encoded inside envelopes when publishing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OfferEncoding {
+    /// Compressed offer bytes, saves the bech32 overhead
+    #[default]
+    Binary,
+    /// `offer1...` strings, for peers that don't understand binary offers
+    Bech32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub message_type: MessageType,
+    /// Reserved for future use, unknown flags are ignored
+    pub flags: u32,
+    /// When the message was first published
+    pub timestamp: Option<SystemTime>,
+    pub payload: Bytes,
+}
+
+impl Envelope {
+    /// Wraps an offer string, timestamped now
+    pub fn offer(offer: Bytes) -> Envelope {
+        Envelope::new(MessageType::Offer, offer)
+    }
+
+    /// Wraps compressed offer bytes, timestamped now
+    pub fn binary_offer(compressed: Bytes) -> Envelope {
+        Envelope::new(MessageType::BinaryOffer, compressed)
+    }
+
+    /// Wraps a cancellation, timestamped now
+    pub fn cancellation(cancellation: &Cancellation) -> Envelope {
+        Envelope::new(
+            MessageType::OfferCancellation,
+            cancellation.to_bytes().into(),
+        )
+    }
+
+    /// Wraps a notice that an offer was taken, timestamped now
+    pub fn offer_taken(notice: &TakenNotice) -> Envelope {
+        Envelope::new(MessageType::OfferTaken, notice.to_bytes().into())
+    }
+
+    fn new(message_type: MessageType, payload: Bytes) -> Envelope {
+        Envelope {
+            message_type,
+            flags: 0,
+            timestamp: Some(SystemTime::now()),
+            payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(19 + self.payload.len());
+        bytes.push(ENVELOPE_VERSION);
+        bytes.push(self.message_type.to_u8());
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+
+        match self.timestamp {
+            Some(timestamp) => {
+                let seconds = timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                bytes.push(1);
+                bytes.extend_from_slice(&seconds.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+
+        bytes.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Parses an envelope, the payload shares the memory of `bytes`
+    pub fn from_bytes(bytes: Bytes) -> Result<Envelope, SplashError> {
+        let (envelope, payload) = parse(&bytes)?;
+        let payload_start = bytes.len() - payload.len();
+
+        Ok(Envelope {
+            payload: bytes.slice(payload_start..),
+            ..envelope
+        })
+    }
+}
+
+/// Parses an envelope without copying, the returned envelope has an empty payload
+pub(crate) fn parse(bytes: &[u8]) -> Result<(Envelope, &[u8]), SplashError> {
+    let mut reader = Reader::new(bytes);
+
+    let version = reader.u8().map_err(SplashError::InvalidEnvelope)?;
+    if version != ENVELOPE_VERSION {
+        return Err(SplashError::UnsupportedEnvelopeVersion(version));
+    }
+
+    let message_type = reader.u8().map_err(SplashError::InvalidEnvelope)?;
+    let message_type = MessageType::from_u8(message_type)
+        .ok_or(SplashError::UnsupportedMessageType(message_type))?;
+
+    let flags = reader.u32().map_err(SplashError::InvalidEnvelope)?;
+
+    let timestamp = match reader
+        .optional(|reader| reader.u64())
+        .map_err(SplashError::InvalidEnvelope)?
+    {
+        Some(seconds) => Some(
+            UNIX_EPOCH
+                .checked_add(Duration::from_secs(seconds))
+                .ok_or_else(|| SplashError::InvalidEnvelope("invalid timestamp".to_string()))?,
+        ),
+        None => None,
+    };
+
+    let payload = reader.bytes().map_err(SplashError::InvalidEnvelope)?;
+
+    if !reader.is_empty() {
+        return Err(SplashError::InvalidEnvelope(format!(
+            "{} trailing bytes",
+            reader.remaining()
+        )));
+    }
+
+    let envelope = Envelope {
+        message_type,
+        flags,
+        timestamp,
+        payload: Bytes::new(),
+    };
+
+    Ok((envelope, payload))
+}
+
+// Legacy messages are offer strings, so they start with a printable ASCII character
+pub(crate) fn is_legacy(data: &[u8]) -> bool {
+    data.first().is_some_and(u8::is_ascii_graphic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use hex_literal::hex;
+
+    const MESSAGE_TYPES: [MessageType; 4] = [
+        MessageType::Offer,
+        MessageType::BinaryOffer,
+        MessageType::OfferCancellation,
+        MessageType::OfferTaken,
+    ];
+
+    fn envelope(message_type: MessageType, timestamp: Option<u64>) -> Envelope {
+        Envelope {
+            message_type,
+            flags: 5,
+            timestamp: timestamp.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds)),
+            payload: Bytes::from_static(b"abc"),
+        }
+    }
+
+    #[test]
+    fn encodes_streamable_envelopes() {
+        assert_eq!(
+            envelope(MessageType::BinaryOffer, Some(1_700_000_000)).to_bytes(),
+            hex!("01 02 00000005 01 000000006553f100 00000003 616263")
+        );
+        assert_eq!(
+            envelope(MessageType::OfferTaken, None).to_bytes(),
+            hex!("01 04 00000005 00 00000003 616263")
+        );
+    }
+
+    #[test]
+    fn round_trips_every_message_type() {
+        for message_type in MESSAGE_TYPES {
+            for timestamp in [None, Some(0), Some(1_700_000_000), Some(u64::MAX / 2)] {
+                let envelope = envelope(message_type, timestamp);
+                let bytes = Bytes::from(envelope.to_bytes());
+
+                assert_eq!(Envelope::from_bytes(bytes.clone()).unwrap(), envelope);
+                let (parsed, payload) = parse(&bytes).unwrap();
+                assert_eq!(parsed.payload, Bytes::new());
+                assert_eq!(payload, b"abc");
+            }
+        }
+    }
+
+    #[test]
+    fn drops_fractions_of_seconds() {
+        let envelope = Envelope {
+            timestamp: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_999)),
+            ..envelope(MessageType::Offer, None)
+        };
+
+        let parsed = Envelope::from_bytes(envelope.to_bytes().into()).unwrap();
+        assert_eq!(
+            parsed.timestamp,
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_envelopes() {
+        let valid = envelope(MessageType::Offer, None).to_bytes();
+
+        assert!(matches!(
+            parse(&[2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(SplashError::UnsupportedEnvelopeVersion(2))
+        ));
+        assert!(matches!(
+            parse(&[1, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(SplashError::UnsupportedMessageType(5))
+        ));
+        assert!(matches!(
+            parse(&valid[..valid.len() - 1]),
+            Err(SplashError::InvalidEnvelope(_))
+        ));
+        assert!(matches!(
+            parse(&[&valid[..], &[0]].concat()),
+            Err(SplashError::InvalidEnvelope(_))
+        ));
+        assert!(matches!(parse(&[]), Err(SplashError::InvalidEnvelope(_))));
+    }
+
+    #[test]
+    fn tells_legacy_messages_from_envelopes() {
+        assert!(is_legacy(fixtures::XCH_FOR_CAT.as_bytes()));
+
+        for message_type in MESSAGE_TYPES {
+            assert!(!is_legacy(&envelope(message_type, Some(0)).to_bytes()));
+        }
+        assert!(!is_legacy(b""));
+        assert!(!is_legacy(b" offer1"));
+    }
+}
@@ -0,0 +1,60 @@
+//! Offers shared by the unit tests, in `tests/fixtures`
+//!
+//! [`NFT_FOR_CAT`] is a wallet offer from the Chia wallet SDK test data. The others were made with
+//! the wallet SDK and taken on its simulator, signed for testnet11.
+
+use crate::puzzles;
+use bytes::Bytes;
+use flate2::{Compress, Compression, FlushCompress, Status};
+
+macro_rules! fixture {
+    ($name:literal) => {
+        include_str!(concat!("../tests/fixtures/", $name, ".offer")).trim_ascii_end()
+    };
+}
+
+/// Offers 100 mojos for 250 of a CAT
+pub const XCH_FOR_CAT: &str = fixture!("xch_for_cat");
+/// [`XCH_FOR_CAT`] compressed with the dictionary of every offer version
+pub const XCH_FOR_CAT_VERSIONS: [(u16, &str); 6] = [
+    (1, fixture!("xch_for_cat_v1")),
+    (2, fixture!("xch_for_cat_v2")),
+    (3, fixture!("xch_for_cat_v3")),
+    (4, fixture!("xch_for_cat_v4")),
+    (5, fixture!("xch_for_cat_v5")),
+    (6, XCH_FOR_CAT),
+];
+/// Offers 500 of a CAT for 300 mojos
+pub const CAT_FOR_XCH: &str = fixture!("cat_for_xch");
+/// Offers 400 of a CAT for 700 of another CAT
+pub const CAT_FOR_CAT: &str = fixture!("cat_for_cat");
+/// Offers 1000 mojos for an NFT
+pub const XCH_FOR_NFT: &str = fixture!("xch_for_nft");
+/// Offers an NFT for 2000 mojos
+pub const NFT_FOR_XCH: &str = fixture!("nft_for_xch");
+/// Offers an NFT for 100000000000 of a CAT
+pub const NFT_FOR_CAT: &str = fixture!("nft_for_cat");
+/// Offers 100 mojos for 1000 of a CAT until 2033-05-18 03:33:20 UTC
+pub const EXPIRING: &str = fixture!("expiring");
+
+/// Compresses a serialized spend bundle the way the Chia wallet does
+pub fn compress(version: u16, spend_bundle: &[u8]) -> Bytes {
+    let zdict = puzzles::zdict_for_version(version).expect("known version");
+    let mut compress = Compress::new(Compression::best(), true);
+    compress.set_dictionary(&zdict).expect("valid dictionary");
+
+    let mut output = Vec::with_capacity(spend_bundle.len() + 1024);
+    while compress
+        .compress_vec(
+            &spend_bundle[compress.total_in() as usize..],
+            &mut output,
+            FlushCompress::Finish,
+        )
+        .expect("compressible")
+        != Status::StreamEnd
+    {
+        output.reserve(output.capacity());
+    }
+
+    [&version.to_be_bytes()[..], &output].concat().into()
+}
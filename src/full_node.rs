@@ -0,0 +1,396 @@
+use crate::offer::Coin;
+use crate::{MessageAcceptance, MessageValidator, Offer, SplashError};
+use async_trait::async_trait;
+use libp2p::PeerId;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client for the RPC API of a Chia full node
+#[derive(Clone)]
+pub struct FullNodeRpc {
+    url: String,
+    client: reqwest::Client,
+}
+
+/// A coin as tracked by the full node
+#[derive(Debug, Clone)]
+pub struct CoinRecord {
+    pub coin: Coin,
+    pub confirmed_block_index: u32,
+    /// Height the coin was spent at, 0 while it's unspent
+    pub spent_block_index: u32,
+    pub coinbase: bool,
+    pub timestamp: u64,
+}
+
+impl CoinRecord {
+    pub fn is_spent(&self) -> bool {
+        self.spent_block_index > 0
+    }
+}
+
+/// Source of coin states, implemented by [`FullNodeRpc`]. Implement it to watch the chain through
+/// something else, e.g. a wallet or a mock.
+#[async_trait]
+pub trait CoinStateSource: Send + Sync {
+    /// Looks up coins by their coin ID including spent coins, unknown coins are omitted
+    async fn coin_records(&self, coin_ids: &[[u8; 32]]) -> Result<Vec<CoinRecord>, SplashError>;
+}
+
+#[derive(Deserialize)]
+struct CoinRecordsResponse {
+    success: bool,
+    error: Option<String>,
+    #[serde(default)]
+    coin_records: Vec<RpcCoinRecord>,
+}
+
+#[derive(Deserialize)]
+struct RpcCoinRecord {
+    coin: RpcCoin,
+    confirmed_block_index: u32,
+    spent_block_index: u32,
+    coinbase: bool,
+    timestamp: u64,
+}
+
+#[derive(Deserialize)]
+struct RpcCoin {
+    parent_coin_info: String,
+    puzzle_hash: String,
+    amount: u64,
+}
+
+impl FullNodeRpc {
+    /// Connects to the RPC API at `url`, e.g. `https://localhost:8555`. Chia full nodes require a
+    /// client certificate, see [`FullNodeRpc::with_client_certificate`].
+    pub fn new(url: impl Into<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(RPC_TIMEOUT)
+            .build()
+            .expect("default client configuration is valid");
+
+        FullNodeRpc {
+            url: url.into(),
+            client,
+        }
+    }
+
+    /// Connects to the RPC API at `url` authenticating with the full node's private certificate
+    /// and key, usually `config/ssl/full_node/private_full_node.{crt,key}`. By default Chia full
+    /// nodes use a server certificate signed by their own CA, with `self_signed` it's accepted
+    /// without verification. Only use it for nodes reached over a trusted network, like localhost.
+    pub fn with_client_certificate(
+        url: impl Into<String>,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+        self_signed: bool,
+    ) -> Result<Self, SplashError> {
+        let identity = reqwest::Identity::from_pem(&[cert_pem, key_pem].concat())
+            .map_err(|e| SplashError::FullNodeRpc(e.to_string()))?;
+
+        let client = reqwest::Client::builder()
+            .timeout(RPC_TIMEOUT)
+            .use_rustls_tls()
+            .identity(identity)
+            .danger_accept_invalid_certs(self_signed)
+            .build()
+            .map_err(|e| SplashError::FullNodeRpc(e.to_string()))?;
+
+        Ok(FullNodeRpc {
+            url: url.into(),
+            client,
+        })
+    }
+
+    /// Looks up coins by their coin ID, coins unknown to the full node are omitted
+    pub async fn get_coin_records_by_names(
+        &self,
+        names: &[[u8; 32]],
+        include_spent_coins: bool,
+    ) -> Result<Vec<CoinRecord>, SplashError> {
+        let names: Vec<String> = names
+            .iter()
+            .map(|name| format!("0x{}", hex::encode(name)))
+            .collect();
+
+        let response: CoinRecordsResponse = self
+            .client
+            .post(format!(
+                "{}/get_coin_records_by_names",
+                self.url.trim_end_matches('/')
+            ))
+            .json(&json!({
+                "names": names,
+                "include_spent_coins": include_spent_coins,
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| SplashError::FullNodeRpc(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| SplashError::FullNodeRpc(e.to_string()))?;
+
+        if !response.success {
+            return Err(SplashError::FullNodeRpc(
+                response
+                    .error
+                    .unwrap_or_else(|| "request failed".to_string()),
+            ));
+        }
+
+        response
+            .coin_records
+            .into_iter()
+            .map(|record| {
+                Ok(CoinRecord {
+                    coin: Coin {
+                        parent_coin_info: parse_bytes32(&record.coin.parent_coin_info)?,
+                        puzzle_hash: parse_bytes32(&record.coin.puzzle_hash)?,
+                        amount: record.coin.amount,
+                    },
+                    confirmed_block_index: record.confirmed_block_index,
+                    spent_block_index: record.spent_block_index,
+                    coinbase: record.coinbase,
+                    timestamp: record.timestamp,
+                })
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl CoinStateSource for FullNodeRpc {
+    async fn coin_records(&self, coin_ids: &[[u8; 32]]) -> Result<Vec<CoinRecord>, SplashError> {
+        self.get_coin_records_by_names(coin_ids, true).await
+    }
+}
+
+fn parse_bytes32(value: &str) -> Result<[u8; 32], SplashError> {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(value.trim_start_matches("0x"), &mut bytes)
+        .map_err(|_| SplashError::FullNodeRpc(format!("invalid bytes32 {}", value)))?;
+    Ok(bytes)
+}
+
+/// Ignores offers spending coins that are already spent or unknown to the full node
+///
+/// Taken offers keep circulating for a while, so they are dropped without penalizing the peer.
+/// Offers are accepted when the full node can't be reached.
+pub struct CoinStateValidator {
+    source: Arc<dyn CoinStateSource>,
+}
+
+impl CoinStateValidator {
+    pub fn new(source: impl CoinStateSource + 'static) -> Self {
+        CoinStateValidator {
+            source: Arc::new(source),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageValidator for CoinStateValidator {
+    async fn validate(&self, offer: &Offer, _propagation_source: &PeerId) -> MessageAcceptance {
+        let coin_ids = offer.coin_ids();
+
+        if coin_ids.is_empty() {
+            return MessageAcceptance::Accept;
+        }
+
+        let records = match self.source.coin_records(&coin_ids).await {
+            Ok(records) => records,
+            Err(e) => {
+                warn!("Coin state of offer {} unknown: {}", offer.id(), e);
+                return MessageAcceptance::Accept;
+            }
+        };
+
+        for coin_id in &coin_ids {
+            match records
+                .iter()
+                .find(|record| record.coin.coin_id() == *coin_id)
+            {
+                Some(record) if !record.is_spent() => {}
+                Some(_) => {
+                    debug!(
+                        "Ignoring offer {}, coin {} is spent",
+                        offer.id(),
+                        hex::encode(coin_id)
+                    );
+                    return MessageAcceptance::Ignore;
+                }
+                None => {
+                    debug!(
+                        "Ignoring offer {}, coin {} doesn't exist",
+                        offer.id(),
+                        hex::encode(coin_id)
+                    );
+                    return MessageAcceptance::Ignore;
+                }
+            }
+        }
+
+        MessageAcceptance::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use serde_json::Value;
+    use std::net::SocketAddr;
+    use warp::Filter;
+
+    /// Serves `get_coin_records_by_names` on a local port, answering every request with
+    /// `response` and `status`
+    fn serve(status: u16, response: Value) -> SocketAddr {
+        let route = warp::post()
+            .and(warp::path!("get_coin_records_by_names"))
+            .and(warp::body::json())
+            .map(move |request: Value| {
+                assert_eq!(request["include_spent_coins"], true);
+                warp::reply::with_status(
+                    warp::reply::json(&response),
+                    warp::http::StatusCode::from_u16(status).unwrap(),
+                )
+            });
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        address
+    }
+
+    fn rpc(address: SocketAddr) -> FullNodeRpc {
+        FullNodeRpc::new(format!("http://{}/", address))
+    }
+
+    /// A successful response with the record of the first coin `offer` spends
+    fn coin_records_response(offer: &Offer, spent_block_index: u32) -> Value {
+        let coin = &offer.spend_bundle().coin_spends[0].coin;
+        json!({
+            "success": true,
+            "coin_records": [{
+                "coin": {
+                    "parent_coin_info": format!("0x{}", hex::encode(coin.parent_coin_info)),
+                    "puzzle_hash": format!("0x{}", hex::encode(coin.puzzle_hash)),
+                    "amount": coin.amount,
+                },
+                "confirmed_block_index": 100,
+                "spent_block_index": spent_block_index,
+                "coinbase": false,
+                "timestamp": 1_700_000_000,
+            }],
+        })
+    }
+
+    async fn validate(address: SocketAddr, offer: &Offer) -> MessageAcceptance {
+        CoinStateValidator::new(rpc(address))
+            .validate(offer, &PeerId::random())
+            .await
+    }
+
+    #[tokio::test]
+    async fn parses_coin_records() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let address = serve(200, coin_records_response(&offer, 120));
+
+        let records = rpc(address).coin_records(&offer.coin_ids()).await.unwrap();
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].coin.coin_id(), offer.coin_ids()[0]);
+        assert_eq!(records[0].confirmed_block_index, 100);
+        assert_eq!(records[0].spent_block_index, 120);
+        assert!(records[0].is_spent());
+        assert_eq!(records[0].timestamp, 1_700_000_000);
+    }
+
+    #[tokio::test]
+    async fn ignores_offers_with_spent_coins() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let address = serve(200, coin_records_response(&offer, 120));
+
+        assert!(matches!(
+            validate(address, &offer).await,
+            MessageAcceptance::Ignore
+        ));
+    }
+
+    #[tokio::test]
+    async fn accepts_offers_with_unspent_coins() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let address = serve(200, coin_records_response(&offer, 0));
+
+        assert!(matches!(
+            validate(address, &offer).await,
+            MessageAcceptance::Accept
+        ));
+    }
+
+    #[tokio::test]
+    async fn ignores_offers_with_unknown_coins() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let address = serve(200, json!({ "success": true, "coin_records": [] }));
+
+        assert!(matches!(
+            validate(address, &offer).await,
+            MessageAcceptance::Ignore
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_errors() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+
+        let failed = serve(200, json!({ "success": false, "error": "node is syncing" }));
+        assert!(matches!(
+            rpc(failed).coin_records(&offer.coin_ids()).await,
+            Err(SplashError::FullNodeRpc(e)) if e == "node is syncing"
+        ));
+
+        let unavailable = serve(503, json!({}));
+        assert!(matches!(
+            rpc(unavailable).coin_records(&offer.coin_ids()).await,
+            Err(SplashError::FullNodeRpc(_))
+        ));
+
+        let malformed = serve(
+            200,
+            json!({
+                "success": true,
+                "coin_records": [{
+                    "coin": { "parent_coin_info": "0x00", "puzzle_hash": "0x00", "amount": 1 },
+                    "confirmed_block_index": 1,
+                    "spent_block_index": 0,
+                    "coinbase": false,
+                    "timestamp": 0,
+                }],
+            }),
+        );
+        assert!(matches!(
+            rpc(malformed).coin_records(&offer.coin_ids()).await,
+            Err(SplashError::FullNodeRpc(_))
+        ));
+
+        // Offers are accepted while the full node can't tell
+        assert!(matches!(
+            validate(unavailable, &offer).await,
+            MessageAcceptance::Accept
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_client_certificates() {
+        assert!(matches!(
+            FullNodeRpc::with_client_certificate("https://localhost:8555", b"cert", b"key", true),
+            Err(SplashError::FullNodeRpc(_))
+        ));
+    }
+}
@@ -1,18 +1,79 @@
+use bytes::Bytes;
 use futures::stream::StreamExt;
-use libp2p::gossipsub::MessageAcceptance;
+use hex_literal::hex;
+use libp2p::core::transport::OptionalTransport;
+use libp2p::core::{upgrade, Transport};
+use libp2p::kad::store::RecordStore;
 use libp2p::multiaddr::Protocol;
-use libp2p::{gossipsub, kad, noise, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux};
-use libp2p::{identify, identity, Multiaddr, PeerId, StreamProtocol};
-use log::warn;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use libp2p::{gossipsub, kad, noise, quic, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp};
+use libp2p::{identify, identity, request_response, Multiaddr, PeerId, StreamProtocol};
+use libp2p::{websocket, yamux};
+use log::{debug, warn};
+use lru::LruCache;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::num::{NonZeroU8, NonZeroUsize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio::{io, select, time};
+pub mod assets;
+mod broadcast_delay;
+pub mod cancellation;
+mod catchup;
+pub mod conditions;
 mod dns;
+pub mod envelope;
+#[cfg(test)]
+mod fixtures;
+pub mod full_node;
+mod message_id;
+pub mod offer;
+mod offer_cache;
+mod puzzles;
+mod records;
+#[cfg(feature = "bls")]
+mod signature;
+mod socks;
+mod stem;
+mod sync;
+pub mod taken;
+mod validator;
+
+pub use assets::Asset;
+use broadcast_delay::BroadcastDelay;
+pub use cancellation::Cancellation;
+use catchup::{CatchupCodec, CatchupRequest, RecentOffers};
+pub use envelope::{Envelope, MessageType, OfferEncoding};
+pub use full_node::{CoinStateSource, CoinStateValidator, FullNodeRpc};
+pub use libp2p::gossipsub::MessageAcceptance;
+use message_id::MessageIds;
+pub use offer::{Offer, OfferId};
+use offer_cache::OfferCache;
+use socks::Socks5Transport;
+use stem::{Stem, StemCodec, StemOffer, StemRequest};
+use sync::{BloomFilter, SyncCodec, SyncRequest};
+pub use taken::TakenNotice;
+use taken::{Observed, Watch};
+pub use validator::MessageValidator;
+use validator::{Origin, Received, TopicKind, ValidationJob, ValidationResult};
 
 const MAX_MESSAGE_SIZE: usize = 300 * 1024;
+const DEFAULT_VALIDATION_QUEUE_SIZE: usize = 256;
+const RECENT_OFFERS_CAPACITY: usize = 4096;
+const WATCHED_OFFERS_CAPACITY: usize = 4096;
+
+// Number of peers asked for recent offers after connecting
+const CATCHUP_PEERS: usize = 3;
+const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+// Additional data of AGG_SIG_ME signatures, offers are only valid on the network they were made for
+const MAINNET_GENESIS_CHALLENGE: [u8; 32] =
+    hex!("ccd5bb71183532bff220ba46c268991a3ff07eb358e8255a65c30a2dce0e5fbb");
+const TESTNET11_GENESIS_CHALLENGE: [u8; 32] =
+    hex!("37a90eb5185a9c4439a91ddc98bbadce7b4feba060d50116a067de66bf236615");
 
 #[derive(Error, Debug)]
 pub enum SplashError {
@@ -20,17 +81,144 @@ pub enum SplashError {
     MessageTooLarge(usize),
     #[error("Invalid message format: not a valid bech32 string")]
     InvalidMessageFormat,
+    #[error("Invalid offer prefix: expected \"offer\", got \"{0}\"")]
+    InvalidOfferPrefix(String),
+    #[error("Invalid offer encoding: not a valid bech32m offer payload")]
+    InvalidOfferEncoding,
+    #[error("Unsupported offer compression version {0}")]
+    UnsupportedOfferVersion(u16),
+    #[error("Failed to decompress offer: {0}")]
+    OfferDecompressionFailed(String),
+    #[error("Invalid offer spend bundle: {0}")]
+    InvalidSpendBundle(String),
+    #[error("Offer puzzle failed: {0}")]
+    PuzzleFailed(String),
+    #[error("Offer {0} has expired")]
+    OfferExpired(OfferId),
+    #[error("Offer {0} has an invalid aggregated signature")]
+    InvalidSignature(OfferId),
+    #[error("Full node RPC failed: {0}")]
+    FullNodeRpc(String),
+    #[error("Invalid message envelope: {0}")]
+    InvalidEnvelope(String),
+    #[error("Unsupported message envelope version {0}")]
+    UnsupportedEnvelopeVersion(u8),
+    #[error("Unsupported message type {0}")]
+    UnsupportedMessageType(u8),
+    #[error("Invalid offer cancellation: {0}")]
+    InvalidCancellation(String),
+    #[error("Invalid offer taken notice: {0}")]
+    InvalidTakenNotice(String),
+    #[error("Invalid asset \"{0}\": expected xch or a CAT asset ID")]
+    InvalidAsset(String),
+    #[error("Topic {0} is reserved for offers")]
+    ReservedTopic(String),
+    #[error("Failed to subscribe: {0}")]
+    SubscriptionFailed(gossipsub::SubscriptionError),
+    #[error("Failed to publish message: {0}")]
+    PublishFailed(gossipsub::PublishError),
+    #[error("Invalid offer record: {0}")]
+    InvalidOfferRecord(String),
+    #[error("The offer cache is disabled, see Splash::with_offer_cache")]
+    OfferCacheDisabled,
     #[error("Failed to send message to network")]
     SendError,
 }
 
+/// A validated offer received from the network
+#[derive(Debug, Clone)]
+pub struct ReceivedOffer {
+    pub offer_id: OfferId,
+    /// Compressed offer bytes, use [`ReceivedOffer::to_bech32`] for the `offer1...` string
+    pub compressed_offer: Bytes,
+    pub message_id: gossipsub::MessageId,
+    /// Topic the offer was first received on
+    pub topic: gossipsub::TopicHash,
+    pub propagation_source: PeerId,
+    pub received_at: SystemTime,
+    pub expires_at: Option<SystemTime>,
+    pub expires_at_height: Option<u32>,
+    /// Assets offered or requested by the offer
+    pub assets: Vec<Asset>,
+    /// Type of the envelope the offer was received in, `None` for legacy messages
+    pub message_type: Option<MessageType>,
+    /// Publish time claimed by the envelope, `None` for legacy messages
+    pub published_at: Option<SystemTime>,
+}
+
+impl ReceivedOffer {
+    pub fn to_bech32(&self) -> String {
+        offer::encode_bech32(&self.compressed_offer)
+    }
+}
+
+/// A verified cancellation received from the network
+#[derive(Debug, Clone)]
+pub struct ReceivedCancellation {
+    pub offer_id: OfferId,
+    /// Key that signed the cancellation, it controls one of the offer's coins
+    pub public_key: [u8; 48],
+    pub message_id: gossipsub::MessageId,
+    pub topic: gossipsub::TopicHash,
+    pub propagation_source: PeerId,
+    pub received_at: SystemTime,
+    /// Publish time claimed by the envelope
+    pub published_at: Option<SystemTime>,
+}
+
+/// An offer that was taken, reported by a peer or observed through our own coin state source
+#[derive(Debug, Clone)]
+pub struct TakenOffer {
+    pub offer_id: OfferId,
+    /// The offer's coin that was spent
+    pub coin_id: [u8; 32],
+    pub spent_height: u32,
+    /// `None` if we observed the spend ourselves
+    pub message_id: Option<gossipsub::MessageId>,
+    /// `None` if we observed the spend ourselves
+    pub topic: Option<gossipsub::TopicHash>,
+    /// `None` if we observed the spend ourselves
+    pub propagation_source: Option<PeerId>,
+    pub received_at: SystemTime,
+    /// Publish time claimed by the envelope
+    pub published_at: Option<SystemTime>,
+}
+
+/// A message received on an application topic, see [`Splash::subscribe`]
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub topic: gossipsub::TopicHash,
+    pub data: Bytes,
+    pub message_id: gossipsub::MessageId,
+    pub propagation_source: PeerId,
+    pub received_at: SystemTime,
+}
+
+/// Who published gossipsub messages are attributed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Authorship {
+    /// Messages are signed with a throwaway key, for peers that reject unsigned messages
+    Signed,
+    /// Messages are published without author, sequence number and signature. Incoming messages
+    /// of peers that still sign are checked, use this while peers migrate.
+    #[default]
+    Transitional,
+    /// Like [`Authorship::Transitional`], but authors and signatures of incoming messages are
+    /// ignored, use this once peers stopped signing
+    Anonymous,
+}
+
 pub enum SplashEvent {
     Initialized(PeerId),
     PeerConnected(PeerId),
     PeerDisconnected(PeerId),
-    MessageReceived(String),
+    MessageReceived(ReceivedOffer),
+    OfferCancelled(ReceivedCancellation),
+    OfferTaken(TakenOffer),
+    ApplicationMessage(ReceivedMessage),
     NewListenAddress(Multiaddr),
     MessageBroadcasted(String),
+    CancellationBroadcasted(OfferId),
     MessageBroadcastFailed(gossipsub::PublishError),
 }
 
@@ -39,8 +227,61 @@ pub struct Splash {
     pub known_peers: Vec<Multiaddr>,
     pub keys: identity::Keypair,
     network_name: String,
-    submission: Sender<Vec<u8>>,
-    submission_receiver: Option<Receiver<Vec<u8>>>,
+    genesis_challenge: [u8; 32],
+    validators: Vec<Arc<dyn MessageValidator>>,
+    validation_workers: usize,
+    validation_queue_size: usize,
+    catchup_window: Option<Duration>,
+    sync_interval: Option<Duration>,
+    offer_records: bool,
+    stem_phase: bool,
+    broadcast_delay: Option<Duration>,
+    socks5_proxy: Option<SocketAddr>,
+    content_addressed_ids_from: SystemTime,
+    // Capacity and TTL of the offer cache
+    offer_cache: Option<(usize, Duration)>,
+    offer_encoding: OfferEncoding,
+    authorship: Authorship,
+    asset_subscriptions: Vec<Asset>,
+    coin_state: Option<Arc<dyn CoinStateSource>>,
+    submission: Sender<Submission>,
+    submission_receiver: Option<Receiver<Submission>>,
+}
+
+enum Submission {
+    // An offer to publish, both as `offer1...` string and compressed bytes
+    Offer {
+        offer_id: OfferId,
+        offer: Bytes,
+        compressed: Bytes,
+        assets: Vec<Asset>,
+        expires_at: Option<SystemTime>,
+    },
+    #[cfg(feature = "bls")]
+    Cancellation(Cancellation, Vec<Asset>),
+    Subscribe(
+        gossipsub::IdentTopic,
+        oneshot::Sender<Result<bool, SplashError>>,
+    ),
+    Unsubscribe(
+        gossipsub::IdentTopic,
+        oneshot::Sender<Result<bool, SplashError>>,
+    ),
+    Publish(
+        gossipsub::IdentTopic,
+        Bytes,
+        oneshot::Sender<Result<gossipsub::MessageId, SplashError>>,
+    ),
+    // Looks up the compressed offer in the DHT
+    GetOffer(OfferId, oneshot::Sender<Result<Option<Bytes>, SplashError>>),
+    CachedOffer(
+        OfferId,
+        oneshot::Sender<Result<Option<ReceivedOffer>, SplashError>>,
+    ),
+    CachedOffers(
+        SystemTime,
+        oneshot::Sender<Result<Vec<ReceivedOffer>, SplashError>>,
+    ),
 }
 
 pub struct SplashContext {
@@ -55,6 +296,22 @@ impl Clone for Splash {
             known_peers: self.known_peers.clone(),
             keys: self.keys.clone(),
             network_name: self.network_name.clone(),
+            genesis_challenge: self.genesis_challenge,
+            validators: self.validators.clone(),
+            validation_workers: self.validation_workers,
+            validation_queue_size: self.validation_queue_size,
+            catchup_window: self.catchup_window,
+            sync_interval: self.sync_interval,
+            offer_records: self.offer_records,
+            stem_phase: self.stem_phase,
+            broadcast_delay: self.broadcast_delay,
+            socks5_proxy: self.socks5_proxy,
+            content_addressed_ids_from: self.content_addressed_ids_from,
+            offer_cache: self.offer_cache,
+            offer_encoding: self.offer_encoding,
+            authorship: self.authorship,
+            asset_subscriptions: self.asset_subscriptions.clone(),
+            coin_state: self.coin_state.clone(),
             submission: self.submission.clone(),
             submission_receiver: None,
         }
@@ -66,6 +323,9 @@ struct SplashBehaviour {
     gossipsub: gossipsub::Behaviour,
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
     identify: identify::Behaviour,
+    catchup: request_response::Behaviour<CatchupCodec>,
+    sync: request_response::Behaviour<SyncCodec>,
+    stem: request_response::Behaviour<StemCodec>,
 }
 
 impl Default for Splash {
@@ -76,44 +336,189 @@ impl Default for Splash {
 
 impl Splash {
     pub fn new() -> Splash {
-        let (submission_sender, submission_receiver) = tokio::sync::mpsc::channel::<Vec<u8>>(100);
+        let (submission_sender, submission_receiver) =
+            tokio::sync::mpsc::channel::<Submission>(100);
 
         Splash {
             known_peers: Vec::new(),
             listen_addresses: Vec::new(),
             keys: identity::Keypair::generate_ed25519(),
             network_name: "splash".to_string(),
+            genesis_challenge: MAINNET_GENESIS_CHALLENGE,
+            validators: Vec::new(),
+            validation_workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            validation_queue_size: DEFAULT_VALIDATION_QUEUE_SIZE,
+            catchup_window: None,
+            sync_interval: Some(DEFAULT_SYNC_INTERVAL),
+            offer_records: false,
+            stem_phase: true,
+            broadcast_delay: None,
+            socks5_proxy: None,
+            content_addressed_ids_from: UNIX_EPOCH + message_id::CONTENT_ADDRESSED_IDS_ACTIVATION,
+            offer_cache: None,
+            offer_encoding: OfferEncoding::default(),
+            authorship: Authorship::default(),
+            asset_subscriptions: Vec::new(),
+            coin_state: None,
             submission: submission_sender,
             submission_receiver: Some(submission_receiver),
         }
     }
 
-    pub fn validate_message(message: &str) -> Result<(), SplashError> {
+    pub fn validate_message(message: &str) -> Result<Offer, SplashError> {
         if message.len() > MAX_MESSAGE_SIZE {
             return Err(SplashError::MessageTooLarge(MAX_MESSAGE_SIZE));
         }
 
-        /*
-        if !message.starts_with("message1") || bech32::decode(message).is_err() {
-            return Err(SplashError::InvalidMessageFormat);
+        Splash::validate_offer(Offer::decode(message)?)
+    }
+
+    /// Validates compressed offer bytes, see [`Splash::validate_message`]
+    pub fn validate_binary_message(message: Bytes) -> Result<Offer, SplashError> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(SplashError::MessageTooLarge(MAX_MESSAGE_SIZE));
         }
-        */
-        // TODO: more validations?
 
-        Ok(())
+        Splash::validate_offer(Offer::from_compressed(message)?)
+    }
+
+    fn validate_offer(offer: Offer) -> Result<Offer, SplashError> {
+        if offer.is_expired(SystemTime::now()) {
+            return Err(SplashError::OfferExpired(offer.id()));
+        }
+
+        Ok(offer)
     }
 
     pub async fn broadcast_message(&self, message: &str) -> Result<(), SplashError> {
-        Splash::validate_message(message)?;
+        let offer = Splash::validate_message(message)?;
+
+        // Don't get penalized by peers for relaying an offer with a bad signature
+        #[cfg(feature = "bls")]
+        signature::verify_offer(&offer, &self.genesis_challenge)?;
+
+        let submission = Submission::Offer {
+            offer_id: offer.id(),
+            offer: Bytes::copy_from_slice(message.as_bytes()),
+            compressed: offer.compressed().clone(),
+            assets: offer.assets(),
+            expires_at: offer.expires_at(),
+        };
 
         self.submission
-            .send(message.as_bytes().to_vec())
+            .send(submission)
             .await
             .map_err(|_| SplashError::SendError)?;
 
         Ok(())
     }
 
+    /// Announces that `offer` was cancelled. The announcement is signed with `secret_key`, which
+    /// has to be the key of one of the coins the offer spends, for standard puzzles that's the
+    /// synthetic secret key.
+    #[cfg(feature = "bls")]
+    pub async fn cancel_offer(
+        &self,
+        offer: &str,
+        secret_key: &[u8; 32],
+    ) -> Result<OfferId, SplashError> {
+        let offer = Splash::validate_message(offer)?;
+        let cancellation =
+            signature::sign_cancellation(&offer, secret_key, &self.genesis_challenge)?;
+
+        self.submission
+            .send(Submission::Cancellation(cancellation, offer.assets()))
+            .await
+            .map_err(|_| SplashError::SendError)?;
+
+        Ok(offer.id())
+    }
+
+    /// Subscribes to an application topic, messages received on it are reported as
+    /// [`SplashEvent::ApplicationMessage`] once the validators accepted them. Returns `false` if
+    /// we were already subscribed.
+    pub async fn subscribe(&self, topic: &str) -> Result<bool, SplashError> {
+        let topic = self.application_topic(topic)?;
+        self.request(|reply| Submission::Subscribe(topic, reply))
+            .await
+    }
+
+    /// Unsubscribes from an application topic. Returns `false` if we weren't subscribed.
+    pub async fn unsubscribe(&self, topic: &str) -> Result<bool, SplashError> {
+        let topic = self.application_topic(topic)?;
+        self.request(|reply| Submission::Unsubscribe(topic, reply))
+            .await
+    }
+
+    /// Publishes `data` as is on an application topic, we don't need to be subscribed to it
+    pub async fn publish(
+        &self,
+        topic: &str,
+        data: impl Into<Bytes>,
+    ) -> Result<gossipsub::MessageId, SplashError> {
+        let topic = self.application_topic(topic)?;
+        let data = data.into();
+        self.request(|reply| Submission::Publish(topic, data, reply))
+            .await
+    }
+
+    /// Looks up an offer in the DHT, offers are only found if a node published them, see
+    /// [`Splash::with_offer_records`]
+    pub async fn get_offer(&self, offer_id: OfferId) -> Result<Option<Offer>, SplashError> {
+        self.request(|reply| Submission::GetOffer(offer_id, reply))
+            .await?
+            .map(Splash::validate_binary_message)
+            .transpose()
+    }
+
+    /// Returns a received offer from the offer cache, see [`Splash::with_offer_cache`]
+    pub async fn cached_offer(
+        &self,
+        offer_id: OfferId,
+    ) -> Result<Option<ReceivedOffer>, SplashError> {
+        self.request(|reply| Submission::CachedOffer(offer_id, reply))
+            .await
+    }
+
+    /// Returns the cached offers first seen at or after `since`, oldest first
+    pub async fn cached_offers(
+        &self,
+        since: SystemTime,
+    ) -> Result<Vec<ReceivedOffer>, SplashError> {
+        self.request(|reply| Submission::CachedOffers(since, reply))
+            .await
+    }
+
+    // Offer topics only carry messages built by the offer API, keep the generic API off them
+    fn application_topic(&self, topic: &str) -> Result<gossipsub::IdentTopic, SplashError> {
+        let reserved = ["messages", "assets"]
+            .iter()
+            .any(|prefix| topic.starts_with(&format!("/{}/{}/", self.network_name, prefix)));
+
+        if reserved {
+            return Err(SplashError::ReservedTopic(topic.to_string()));
+        }
+
+        Ok(gossipsub::IdentTopic::new(topic))
+    }
+
+    /// Sends a submission to the event loop and waits for its reply
+    async fn request<T>(
+        &self,
+        submission: impl FnOnce(oneshot::Sender<Result<T, SplashError>>) -> Submission,
+    ) -> Result<T, SplashError> {
+        let (reply, result) = oneshot::channel();
+
+        self.submission
+            .send(submission(reply))
+            .await
+            .map_err(|_| SplashError::SendError)?;
+
+        result.await.map_err(|_| SplashError::SendError)?
+    }
+
     pub fn with_listen_addresses(mut self, listen_addresses: Vec<Multiaddr>) -> Self {
         self.listen_addresses = listen_addresses;
         self
@@ -131,11 +536,120 @@ impl Splash {
 
     pub fn with_testnet(mut self) -> Self {
         self.network_name = "splash-testnet".to_string();
+        self.genesis_challenge = TESTNET11_GENESIS_CHALLENGE;
+        self
+    }
+
+    /// Adds a validator that runs on every received offer after the built-in validation
+    pub fn with_validator(mut self, validator: impl MessageValidator + 'static) -> Self {
+        self.validators.push(Arc::new(validator));
+        self
+    }
+
+    /// Sets the number of workers validating received messages, defaults to the number of CPUs
+    pub fn with_validation_workers(mut self, workers: usize) -> Self {
+        self.validation_workers = workers;
+        self
+    }
+
+    /// Sets who the messages we publish are attributed to, defaults to
+    /// [`Authorship::Transitional`]
+    pub fn with_authorship(mut self, authorship: Authorship) -> Self {
+        self.authorship = authorship;
+        self
+    }
+
+    /// Sets how offers are encoded in the message envelopes we publish, defaults to binary
+    pub fn with_offer_encoding(mut self, offer_encoding: OfferEncoding) -> Self {
+        self.offer_encoding = offer_encoding;
+        self
+    }
+
+    /// Only subscribes to the topics of `assets` instead of receiving every offer. Offers are
+    /// published on the topic of each of their assets in addition to the topics for all offers.
+    pub fn with_asset_subscriptions(mut self, assets: Vec<Asset>) -> Self {
+        self.asset_subscriptions = assets;
+        self
+    }
+
+    /// Watches the coins of received offers through `source` and announces offers whose coins get
+    /// spent to the network. Offer taken notices from peers are checked against `source` too.
+    pub fn with_offer_taken_notices(mut self, source: impl CoinStateSource + 'static) -> Self {
+        self.coin_state = Some(Arc::new(source));
+        self
+    }
+
+    /// Asks the first peers we connect to for the offers they received within `window`, so
+    /// offers published while we were offline aren't missed. Peers keep offers for an hour.
+    pub fn with_catchup(mut self, window: Duration) -> Self {
+        self.catchup_window = Some(window);
+        self
+    }
+
+    /// Publishes the offers we broadcast as Kademlia records, so nodes that missed them can look
+    /// them up with [`Splash::get_offer`]. Offers larger than 64 KiB aren't published.
+    pub fn with_offer_records(mut self) -> Self {
+        self.offer_records = true;
+        self
+    }
+
+    /// Whether offers we broadcast are relayed along a random path of peers before they are
+    /// gossiped, hiding that they came from us. Enabled by default, offers peers relay to us are
+    /// passed on either way.
+    pub fn with_stem_phase(mut self, enabled: bool) -> Self {
+        self.stem_phase = enabled;
+        self
+    }
+
+    /// Holds the offers we broadcast for a random time of up to `max_delay`, then sends them along
+    /// with the next offer we relay for a peer the same way, along the stem or gossiped if the stem
+    /// phase is disabled. Their timing then doesn't give away that they are ours. Offers are sent
+    /// after `max_delay` at the latest.
+    pub fn with_broadcast_delay(mut self, max_delay: Duration) -> Self {
+        self.broadcast_delay = Some(max_delay);
+        self
+    }
+
+    /// Dials all peers through the SOCKS5 proxy at `proxy`, such as a local Tor daemon, which can
+    /// also reach `/onion3` addresses. We don't listen for connections or use QUIC then, peers
+    /// only ever see the proxy's address.
+    pub fn with_socks5_proxy(mut self, proxy: SocketAddr) -> Self {
+        self.socks5_proxy = Some(proxy);
+        self
+    }
+
+    /// Sets how often we sync recent offers with a peer to recover offers gossip lost, `None`
+    /// disables it. We still answer the sync requests of peers.
+    pub fn with_sync_interval(mut self, interval: Option<Duration>) -> Self {
+        self.sync_interval = interval;
+        self
+    }
+
+    /// When to switch from legacy to content addressed gossipsub message IDs, 2027-01-01 00:00:00
+    /// UTC by default. Nodes using different message IDs don't recognize each other's duplicates,
+    /// so all nodes of a network have to switch at the same time.
+    pub fn with_content_addressed_ids_from(mut self, activation: SystemTime) -> Self {
+        self.content_addressed_ids_from = activation;
+        self
+    }
+
+    /// Keeps up to `capacity` received offers for `ttl` after they were first seen, or until they
+    /// expire, are taken or cancelled. Query them with [`Splash::cached_offers`].
+    pub fn with_offer_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.offer_cache = Some((capacity, ttl));
+        self
+    }
+
+    /// Sets how many received messages may wait for validation, messages arriving while the
+    /// queue is full are ignored
+    pub fn with_validation_queue_size(mut self, queue_size: usize) -> Self {
+        self.validation_queue_size = queue_size;
         self
     }
 
     pub async fn build(mut self) -> Result<SplashContext, Box<dyn std::error::Error>> {
         let (event_tx, event_rx) = mpsc::channel(100);
+        let message_ids = Arc::new(MessageIds::new(self.content_addressed_ids_from));
 
         // Check if known_peers is empty and resolve from DNS if necessary
         if self.known_peers.is_empty() {
@@ -146,38 +660,93 @@ impl Splash {
 
         let mut swarm = libp2p::SwarmBuilder::with_existing_identity(self.keys.clone())
             .with_tokio()
-            .with_tcp(
-                tcp::Config::default(),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
+            .with_other_transport(|key| {
+                // Either dial everything through the proxy, or use plain TCP
+                let (socks5, tcp) = match self.socks5_proxy {
+                    Some(proxy) => (
+                        OptionalTransport::some(Socks5Transport::new(proxy)),
+                        OptionalTransport::none(),
+                    ),
+                    None => (
+                        OptionalTransport::none(),
+                        OptionalTransport::some(tcp::tokio::Transport::new(tcp::Config::default())),
+                    ),
+                };
+
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                    socks5
+                        .or_transport(tcp)
+                        .upgrade(upgrade::Version::V1Lazy)
+                        .authenticate(noise::Config::new(key)?)
+                        .multiplex(yamux::Config::default()),
+                )
+            })?
+            // WebSocket over TCP or the proxy, for nodes behind HTTP reverse proxies. Listens on
+            // `/ws` only, `/wss` is dialed but not accepted as no TLS server config is set up
+            .with_other_transport(|key| {
+                let (socks5, tcp) = match self.socks5_proxy {
+                    Some(proxy) => (
+                        OptionalTransport::some(Socks5Transport::new(proxy)),
+                        OptionalTransport::none(),
+                    ),
+                    None => (
+                        OptionalTransport::none(),
+                        OptionalTransport::some(libp2p::dns::tokio::Transport::system(
+                            tcp::tokio::Transport::new(tcp::Config::default()),
+                        )?),
+                    ),
+                };
+
+                Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                    websocket::WsConfig::new(socks5.or_transport(tcp))
+                        .upgrade(upgrade::Version::V1Lazy)
+                        .authenticate(noise::Config::new(key)?)
+                        .multiplex(yamux::Config::default()),
+                )
+            })?
+            // QUIC alongside TCP, unless connections have to go through the proxy
+            .with_other_transport(|key| match self.socks5_proxy {
+                Some(_) => OptionalTransport::none(),
+                None => {
+                    OptionalTransport::some(quic::tokio::Transport::new(quic::Config::new(key)))
+                }
+            })?
             .with_behaviour(|key| {
-                // We can take the hash of message and use it as an ID.
-                let unique_message_fn = |message: &gossipsub::Message| {
-                    let mut s = DefaultHasher::new();
-                    message.data.hash(&mut s);
-                    gossipsub::MessageId::from(s.finish().to_string())
+                // Anonymous messages are accepted by peers validating permissively, as all Splash
+                // nodes do. Message IDs are derived from the content, not the author.
+                let (authenticity, validation_mode) = match self.authorship {
+                    // Generate a dummy keypair for signing gossipsub messages
+                    Authorship::Signed => (
+                        gossipsub::MessageAuthenticity::Signed(
+                            identity::Keypair::generate_ed25519(),
+                        ),
+                        gossipsub::ValidationMode::Permissive,
+                    ),
+                    Authorship::Transitional => (
+                        gossipsub::MessageAuthenticity::Anonymous,
+                        gossipsub::ValidationMode::Permissive,
+                    ),
+                    Authorship::Anonymous => (
+                        gossipsub::MessageAuthenticity::Anonymous,
+                        gossipsub::ValidationMode::None,
+                    ),
                 };
 
                 // Set a custom gossipsub configuration
                 let gossipsub_config = gossipsub::ConfigBuilder::default()
                     .heartbeat_interval(Duration::from_secs(5)) // This is set to aid debugging by not cluttering the log space
-                    .message_id_fn(unique_message_fn) // No duplicate messages will be propagated.
+                    .message_id_fn({
+                        let message_ids = message_ids.clone();
+                        move |message| message_ids.message_id(message)
+                    }) // No duplicate messages will be propagated.
                     .max_transmit_size(MAX_MESSAGE_SIZE)
                     .validate_messages()
-                    .validation_mode(gossipsub::ValidationMode::Permissive)
+                    .validation_mode(validation_mode)
                     .build()
-                    .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?; // Temporary hack because `build` does not return a proper `std::error::Error`.
-
-                // Generate a dummy keypair for signing gossipsub messages
-                // TODO: use gossipsub::MessageAuthenticity::RandomAuthor and disable signing for even more privacy (once enough nodes are updated)
-                let dummy_key = identity::Keypair::generate_ed25519();
+                    .map_err(io::Error::other)?; // Temporary hack because `build` does not return a proper `std::error::Error`.
 
                 // build a gossipsub network behaviour
-                let gossipsub = gossipsub::Behaviour::new(
-                    gossipsub::MessageAuthenticity::Signed(dummy_key),
-                    gossipsub_config,
-                )?;
+                let gossipsub = gossipsub::Behaviour::new(authenticity, gossipsub_config)?;
 
                 // Create a Kademlia behaviour.
                 let mut cfg = kad::Config::new(
@@ -186,12 +755,25 @@ impl Splash {
                 );
 
                 cfg.set_query_timeout(Duration::from_secs(60));
-                let store = kad::store::MemoryStore::new(key.public().to_peer_id());
+                // Records of peers are validated before they are stored
+                cfg.set_record_filtering(kad::StoreInserts::FilterBoth);
+                cfg.set_max_packet_size(records::MAX_RECORD_SIZE + 16 * 1024);
+                let store = kad::store::MemoryStore::with_config(
+                    key.public().to_peer_id(),
+                    kad::store::MemoryStoreConfig {
+                        max_value_bytes: records::MAX_RECORD_SIZE,
+                        ..Default::default()
+                    },
+                );
 
                 let mut kademlia =
                     kad::Behaviour::with_config(key.public().to_peer_id(), store, cfg);
 
-                for addr in self.known_peers.iter() {
+                // Addresses are dialed one at a time in the order they were added, try QUIC first
+                // and fall back to TCP
+                let mut known_peers = self.known_peers.clone();
+                known_peers.sort_by_key(|addr| !is_quic(addr));
+                for addr in known_peers.iter() {
                     let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
                         return Err("Expect peer multiaddr to contain peer ID.".into());
                     };
@@ -208,30 +790,141 @@ impl Splash {
                     .with_agent_version(format!("splash/{}", env!("CARGO_PKG_VERSION"))),
                 );
 
+                let catchup = request_response::Behaviour::with_codec(
+                    CatchupCodec,
+                    [(
+                        StreamProtocol::try_from_owned(format!("/{}/catchup/1", self.network_name))
+                            .expect("protocol name is valid"),
+                        request_response::ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                let sync = request_response::Behaviour::with_codec(
+                    SyncCodec,
+                    [(
+                        StreamProtocol::try_from_owned(format!("/{}/sync/1", self.network_name))
+                            .expect("protocol name is valid"),
+                        request_response::ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
+                let stem = request_response::Behaviour::with_codec(
+                    StemCodec,
+                    [(
+                        StreamProtocol::try_from_owned(format!("/{}/stem/1", self.network_name))
+                            .expect("protocol name is valid"),
+                        request_response::ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                );
+
                 Ok(SplashBehaviour {
                     gossipsub,
                     kademlia,
                     identify,
+                    catchup,
+                    sync,
+                    stem,
                 })
             })?
-            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .with_swarm_config(|c| {
+                c.with_idle_connection_timeout(Duration::from_secs(60))
+                    // Dial a peer's addresses in order rather than racing them, so QUIC is preferred
+                    .with_dial_concurrency_factor(NonZeroU8::MIN)
+            })
             .build();
 
         if !self.listen_addresses.is_empty() {
             for addr in self.listen_addresses.iter() {
+                // The WebSocket listener has no certificate, TLS is terminated by a reverse proxy
+                if addr
+                    .iter()
+                    .any(|p| matches!(p, Protocol::Wss(_) | Protocol::Tls))
+                {
+                    return Err(format!(
+                        "Can't listen on {addr}, listen on `/ws` behind a TLS terminating proxy instead"
+                    )
+                    .into());
+                }
                 swarm.listen_on(addr.clone())?;
             }
-        } else {
+        } else if self.socks5_proxy.is_none() {
             // Fallback to default addresses if no listen addresses are provided
             swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
             swarm.listen_on("/ip6/::/tcp/0".parse()?)?;
+            swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse()?)?;
+            swarm.listen_on("/ip6/::/udp/0/quic-v1".parse()?)?;
         }
 
-        // Create a Gossipsub topic
-        let topic = gossipsub::IdentTopic::new(format!("/{}/messages/1", self.network_name));
+        // Create a Gossipsub topic for enveloped messages and one for legacy nodes that only
+        // understand bare offer strings
+        let topic = gossipsub::IdentTopic::new(format!("/{}/messages/2", self.network_name));
+        let legacy_topic = gossipsub::IdentTopic::new(format!("/{}/messages/1", self.network_name));
+
+        // subscribes to our topics, nodes only interested in some assets skip the topics for all
+        // offers
+        let mut topic_kinds = HashMap::from([
+            (topic.hash(), TopicKind::Offers),
+            (legacy_topic.hash(), TopicKind::Offers),
+        ]);
+        if self.asset_subscriptions.is_empty() {
+            swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+            swarm.behaviour_mut().gossipsub.subscribe(&legacy_topic)?;
+        } else {
+            for asset in self.asset_subscriptions.iter() {
+                let asset_topic = asset_topic(&self.network_name, asset);
+                swarm.behaviour_mut().gossipsub.subscribe(&asset_topic)?;
+                topic_kinds.insert(asset_topic.hash(), TopicKind::Asset(*asset));
+            }
+        }
+
+        // Updated peers publish every offer on both topics, only report it once
+        let mut recent_offers =
+            LruCache::new(NonZeroUsize::new(RECENT_OFFERS_CAPACITY).expect("capacity is not zero"));
+
+        // Notices for the same offer may come from several peers and our own watcher
+        let mut taken_offers =
+            LruCache::new(NonZeroUsize::new(RECENT_OFFERS_CAPACITY).expect("capacity is not zero"));
+
+        let (offer_watcher, mut taken_notices) = match self.coin_state.clone() {
+            Some(source) => {
+                let (watcher, notices) = taken::spawn_watcher(source, WATCHED_OFFERS_CAPACITY);
+                (Some(watcher), Some(notices))
+            }
+            None => (None, None),
+        };
 
-        // subscribes to our topic
-        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        // Offers we can hand out to peers catching up or syncing, and the messages they sent us
+        let mut catchup_cache = RecentOffers::new(RECENT_OFFERS_CAPACITY);
+        let mut catchup_messages =
+            LruCache::new(NonZeroUsize::new(RECENT_OFFERS_CAPACITY).expect("capacity is not zero"));
+        let mut catchup_peers = 0;
+        let catchup_window = self.catchup_window;
+        let asset_subscriptions = self.asset_subscriptions.clone();
+
+        let mut offer_cache = self
+            .offer_cache
+            .map(|(capacity, ttl)| OfferCache::new(capacity, ttl));
+
+        let mut sync_interval = self
+            .sync_interval
+            .map(|interval| time::interval_at(time::Instant::now() + interval, interval));
+        let mut sync_round = 0;
+
+        let offer_records = self.offer_records;
+        let mut offer_lookups = HashMap::new();
+
+        // Offers in their stem phase, relayed offers are processed like received offers once they
+        // are fluffed
+        let mut stem = Stem::new(self.stem_phase);
+        let mut stem_requests = HashMap::new();
+        let mut fluffed_offers: VecDeque<ValidationResult> = VecDeque::new();
+
+        // Offers we broadcast, held back for a while if they are delayed
+        let mut broadcasts = VecDeque::new();
+        let mut broadcast_delay = self.broadcast_delay.map(BroadcastDelay::new);
 
         let mut peer_discovery_interval = time::interval(time::Duration::from_secs(10));
 
@@ -241,28 +934,280 @@ impl Splash {
             .take()
             .ok_or("Submission receiver already consumed")?;
 
+        let mut validators: Vec<Arc<dyn MessageValidator>> = Vec::new();
+
+        // Check the signature before any custom validator sees the offer
+        #[cfg(feature = "bls")]
+        validators.push(Arc::new(signature::SignatureValidator::new(
+            self.genesis_challenge,
+        )));
+
+        validators.extend(self.validators.iter().cloned());
+
+        let (validation_queue, mut validation_results) = validator::spawn_validation_workers(
+            self.validation_workers,
+            self.validation_queue_size,
+            validators,
+            self.genesis_challenge,
+            self.coin_state.clone(),
+        );
+
         event_tx
             .send(SplashEvent::Initialized(self.keys.public().to_peer_id()))
             .await
             .ok();
 
+        let offer_encoding = self.offer_encoding;
+        let proxied = self.socks5_proxy.is_some();
+        let network_name = self.network_name.clone();
+
         // Main event loop
         tokio::spawn(async move {
             loop {
                 select! {
-                    Some(message) = submission_receiver.recv() => {
+                    Some(submission) = submission_receiver.recv() => match submission {
+                        Submission::Offer { offer_id, offer, compressed, assets, expires_at } => {
+                            // Don't take our own offer for a relayed one when it comes back
+                            recent_offers.put(offer_id, ());
+
+                            let stem_offer = StemOffer { offer_id, compressed, offer, assets, expires_at, relayed: None };
+                            match broadcast_delay.as_mut() {
+                                Some(delay) => delay.hold(stem_offer),
+                                None => broadcasts.push_back(stem_offer),
+                            }
+                        },
+                        #[cfg(feature = "bls")]
+                        Submission::Cancellation(cancellation, assets) => {
+                            let offer_id = Offer::id_from_compressed(&cancellation.offer);
+                            let envelope = Envelope::cancellation(&cancellation);
+
+                            match (publish(&mut swarm.behaviour_mut().gossipsub, &topic, &network_name, &assets, envelope.to_bytes()), offer_id) {
+                                (Ok(_), Ok(offer_id)) => {
+                                    event_tx.send(SplashEvent::CancellationBroadcasted(offer_id)).await.ok();
+                                },
+                                (Err(e), _) => {
+                                    event_tx.send(SplashEvent::MessageBroadcastFailed(e)).await.ok();
+                                },
+                                (Ok(_), Err(_)) => {},
+                            }
+                        },
+                        Submission::Subscribe(topic, reply) => {
+                            reply.send(swarm.behaviour_mut().gossipsub.subscribe(&topic).map_err(SplashError::SubscriptionFailed)).ok();
+                        },
+                        // Unsubscribing fails if the unsubscription can't be sent to peers
+                        Submission::Unsubscribe(topic, reply) => {
+                            reply.send(swarm.behaviour_mut().gossipsub.unsubscribe(&topic).map_err(SplashError::PublishFailed)).ok();
+                        },
+                        Submission::Publish(topic, data, reply) => {
+                            reply.send(swarm.behaviour_mut().gossipsub.publish(topic, data).map_err(SplashError::PublishFailed)).ok();
+                        },
+                        Submission::GetOffer(offer_id, reply) => {
+                            let query_id = swarm.behaviour_mut().kademlia.get_record(records::record_key(&network_name, &offer_id));
+                            offer_lookups.insert(query_id, reply);
+                        },
+                        Submission::CachedOffer(offer_id, reply) => {
+                            reply.send(offer_cache.as_ref().map(|cache| cache.get(&offer_id)).ok_or(SplashError::OfferCacheDisabled)).ok();
+                        },
+                        Submission::CachedOffers(since, reply) => {
+                            reply.send(offer_cache.as_ref().map(|cache| cache.since(since)).ok_or(SplashError::OfferCacheDisabled)).ok();
+                        },
+                    },
+                    Some(Observed { offer_id, assets, notice }) = async { taken_notices.as_mut()?.recv().await }, if taken_notices.is_some() => {
+                        if taken_offers.put(offer_id, ()).is_none() {
+                            catchup_cache.remove(&offer_id);
+                            swarm.behaviour_mut().kademlia.remove_record(&records::record_key(&network_name, &offer_id));
+                            if let Some(cache) = offer_cache.as_mut() {
+                                cache.remove(&offer_id);
+                            }
 
-                        if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), message.clone()) {
-                            event_tx.send(SplashEvent::MessageBroadcastFailed(e)).await.ok();
+                            if let Err(e) = publish(&mut swarm.behaviour_mut().gossipsub, &topic, &network_name, &assets, Envelope::offer_taken(&notice).to_bytes()) {
+                                event_tx.send(SplashEvent::MessageBroadcastFailed(e)).await.ok();
+                            }
+
+                            let taken = TakenOffer {
+                                offer_id,
+                                coin_id: notice.coin_id,
+                                spent_height: notice.spent_height,
+                                message_id: None,
+                                topic: None,
+                                propagation_source: None,
+                                received_at: SystemTime::now(),
+                                published_at: None,
+                            };
+                            event_tx.send(SplashEvent::OfferTaken(taken)).await.ok();
+                        }
+                    },
+                    Some(offer) = async { broadcasts.pop_front() }, if !broadcasts.is_empty() => {
+                        let successor = stem.successor(&swarm.connected_peers().copied().collect::<Vec<_>>(), None);
+                        if let Some(peer) = successor {
+                            let envelope = Envelope::binary_offer(offer.compressed.clone()).to_bytes().into();
+                            let request_id = swarm.behaviour_mut().stem.send_request(&peer, StemRequest { envelope });
+                            stem_requests.insert(request_id, offer.offer_id);
                         }
 
-                        event_tx.send(SplashEvent::MessageBroadcasted(String::from_utf8_lossy(&message).to_string())).await.ok();
+                        event_tx.send(SplashEvent::MessageBroadcasted(String::from_utf8_lossy(&offer.offer).into_owned())).await.ok();
+                        stem.embargo(offer, successor);
+                    },
+                    Some(offers) = async { Some(broadcast_delay.as_mut()?.overdue().await) }, if broadcast_delay.is_some() => {
+                        broadcasts.extend(offers);
+                    },
+                    offer = stem.expired() => {
+                        let published = fluff(&mut swarm.behaviour_mut().gossipsub, &topic, &legacy_topic, &network_name, offer_encoding, &offer);
+
+                        match offer.relayed {
+                            Some(result) => fluffed_offers.push_back(result),
+                            None => {
+                                if let Err(e) = published {
+                                    event_tx.send(SplashEvent::MessageBroadcastFailed(e)).await.ok();
+                                }
+                                share_offer(&mut catchup_cache, &mut swarm.behaviour_mut().kademlia, &network_name, offer_records, &offer);
+                            },
+                        }
+                    },
+                    Some(mut result) = async { match fluffed_offers.pop_front() {
+                        Some(result) => Some(result),
+                        None => validation_results.recv().await,
+                    } } => {
+                        match result.received.as_ref() {
+                            // Relay offers in their stem phase, they are processed once fluffed
+                            Some(Received::Offer(received, _)) if result.origin == Origin::Stem => {
+                                if recent_offers.contains(&received.offer_id) || stem.is_embargoed(&received.offer_id) {
+                                    continue;
+                                }
+
+                                let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                                let successor = stem.successor(&peers, Some(result.propagation_source));
+                                let stem_offer = StemOffer {
+                                    offer_id: received.offer_id,
+                                    compressed: received.compressed_offer.clone(),
+                                    offer: received.to_bech32().into(),
+                                    assets: received.assets.clone(),
+                                    expires_at: received.expires_at,
+                                    relayed: None,
+                                };
+
+                                if let Some(peer) = successor {
+                                    let envelope = Envelope::binary_offer(stem_offer.compressed.clone()).to_bytes().into();
+                                    let request_id = swarm.behaviour_mut().stem.send_request(&peer, StemRequest { envelope });
+                                    stem_requests.insert(request_id, stem_offer.offer_id);
+
+                                    // Send the offers we held back along the stem together with offers we relay along it
+                                    if let Some(delay) = broadcast_delay.as_mut() {
+                                        broadcasts.extend(delay.ready());
+                                    }
+                                }
+
+                                result.origin = Origin::Peer;
+                                stem.embargo(StemOffer { relayed: Some(result), ..stem_offer }, successor);
+                                continue;
+                            },
+                            Some(_) if result.origin == Origin::Stem => continue,
+                            // Someone fluffed an offer of our stem
+                            Some(Received::Offer(received, _)) => {
+                                if let Some(offer) = stem.lift(&received.offer_id).filter(|offer| offer.relayed.is_none()) {
+                                    share_offer(&mut catchup_cache, &mut swarm.behaviour_mut().kademlia, &network_name, offer_records, &offer);
+                                }
+                            },
+                            _ => {},
+                        }
+
+                        match result.received {
+                            // Peers catching us up or relaying stems send offers for all assets
+                            Some(Received::Offer(received, _)) if result.origin != Origin::Gossip && !asset_subscriptions.is_empty() && !received.assets.iter().any(|asset| asset_subscriptions.contains(asset)) => {},
+                            Some(Received::Offer(received, coin_ids)) => {
+                                let duplicate = recent_offers.put(received.offer_id, ()).is_some();
+                                if !duplicate {
+                                    catchup_cache.insert(received.offer_id, received.compressed_offer.clone(), received.received_at, received.published_at);
+
+                                    if let Some(watcher) = offer_watcher.as_ref().filter(|_| !taken_offers.contains(&received.offer_id)) {
+                                        let watch = Watch::Offer {
+                                            offer_id: received.offer_id,
+                                            compressed: received.compressed_offer.clone(),
+                                            coin_ids,
+                                            assets: received.assets.clone(),
+                                            expires_at: received.expires_at,
+                                        };
+                                        if watcher.try_send(watch).is_err() {
+                                            warn!("Offer watcher busy, not watching offer {}", received.offer_id);
+                                        }
+                                    }
+
+                                    if let Some(cache) = offer_cache.as_mut().filter(|_| !taken_offers.contains(&received.offer_id)) {
+                                        cache.insert(received.clone());
+                                    }
+
+                                    // Without a stem, gossip the offers we held back together with offers we relay
+                                    if let Some(delay) = broadcast_delay.as_mut().filter(|_| result.origin == Origin::Gossip && !stem.is_enabled()) {
+                                        broadcasts.extend(delay.ready());
+                                    }
+
+                                    // Bridge enveloped offers to the legacy nodes connected to us, one hop is enough as
+                                    // legacy nodes relay it among themselves
+                                    if result.origin == Origin::Gossip && received.message_type.is_some() && has_legacy_peers(&swarm.behaviour().gossipsub, &topic, &legacy_topic) {
+                                        swarm.behaviour_mut().gossipsub.publish(legacy_topic.clone(), received.to_bech32()).ok();
+                                    }
+
+                                    event_tx.send(SplashEvent::MessageReceived(received)).await.ok();
+                                }
+                            },
+                            Some(Received::Cancellation(received)) => {
+                                catchup_cache.remove(&received.offer_id);
+                                swarm.behaviour_mut().kademlia.remove_record(&records::record_key(&network_name, &received.offer_id));
+                                if let Some(cache) = offer_cache.as_mut() {
+                                    cache.remove(&received.offer_id);
+                                }
+
+                                event_tx.send(SplashEvent::OfferCancelled(received)).await.ok();
+                            },
+                            Some(Received::Message(received)) => {
+                                event_tx.send(SplashEvent::ApplicationMessage(received)).await.ok();
+                            },
+                            Some(Received::Taken(taken)) => {
+                                if let Some(watcher) = offer_watcher.as_ref() {
+                                    watcher.try_send(Watch::Taken(taken.offer_id)).ok();
+                                }
+
+                                catchup_cache.remove(&taken.offer_id);
+                                swarm.behaviour_mut().kademlia.remove_record(&records::record_key(&network_name, &taken.offer_id));
+                                if let Some(cache) = offer_cache.as_mut() {
+                                    cache.remove(&taken.offer_id);
+                                }
+
+                                if taken_offers.put(taken.offer_id, ()).is_none() {
+                                    event_tx.send(SplashEvent::OfferTaken(taken)).await.ok();
+                                }
+                            },
+                            None => {},
+                        }
+
+                        if result.origin == Origin::Gossip {
+                            swarm.behaviour_mut().gossipsub.report_message_validation_result(&result.message_id, &result.propagation_source, result.acceptance).ok();
+                        }
+                    },
+                    Some(_) = async { Some(sync_interval.as_mut()?.tick().await) }, if sync_interval.is_some() => {
+                        // Take turns, so offers only some of our peers have reach us eventually
+                        let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+                        if !peers.is_empty() {
+                            let peer = peers[sync_round % peers.len()];
+                            sync_round += 1;
+
+                            let mut known = BloomFilter::new(catchup_cache.offer_ids().count(), sync_seed());
+                            for offer_id in catchup_cache.offer_ids() {
+                                known.insert(offer_id);
+                            }
+                            swarm.behaviour_mut().sync.send_request(&peer, SyncRequest { known });
+                        }
                     },
                     _ = peer_discovery_interval.tick() => {
                         swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random());
                     },
                     event = swarm.select_next_some() => match event {
-                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } => {
+                            if let Some(window) = catchup_window.filter(|_| num_established.get() == 1 && catchup_peers < CATCHUP_PEERS) {
+                                swarm.behaviour_mut().catchup.send_request(&peer_id, CatchupRequest { max_age: window });
+                                catchup_peers += 1;
+                            }
+
                             event_tx.send(SplashEvent::PeerConnected(peer_id)).await.ok();
                         },
                         SwarmEvent::ConnectionClosed { peer_id, .. } => {
@@ -273,20 +1218,107 @@ impl Splash {
                             message_id,
                             message,
                         })) => {
-                            let msg_str = String::from_utf8_lossy(&message.data).into_owned();
+                            let job = ValidationJob {
+                                message_id,
+                                propagation_source,
+                                topic_kind: topic_kinds.get(&message.topic).copied().unwrap_or(TopicKind::Application),
+                                topic: message.topic,
+                                origin: Origin::Gossip,
+                                data: Bytes::from(message.data),
+                                received_at: SystemTime::now(),
+                            };
 
-                            match Splash::validate_message(&msg_str) {
-                                Ok(_) => {
-                                    event_tx.send(SplashEvent::MessageReceived(msg_str)).await.ok();
-                                    swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, MessageAcceptance::Accept).ok();
-                                }
-                                Err(e) => {
-                                    warn!("Received invalid message: {}", e);
-                                    swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, MessageAcceptance::Reject).ok();
+                            // Never block the swarm on validation, drop messages instead when overloaded
+                            if let Some(ignored) = validator::enqueue(&validation_queue, job) {
+                                swarm.behaviour_mut().gossipsub.report_message_validation_result(&ignored.message_id, &ignored.propagation_source, ignored.acceptance).ok();
+                            }
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Catchup(request_response::Event::Message { peer, message })) => match message {
+                            request_response::Message::Request { request, channel, .. } => {
+                                let response = catchup_cache.response(request.max_age);
+                                swarm.behaviour_mut().catchup.send_response(channel, response).ok();
+                            },
+                            request_response::Message::Response { response, .. } => {
+                                debug!("Catching up on {} offers from {}", response.messages.len(), peer);
+                                validate_offers(response.messages, peer, &topic, &message_ids, &mut catchup_messages, &validation_queue);
+                            },
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Catchup(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                            debug!("Catching up from {} failed: {}", peer, error);
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Sync(request_response::Event::Message { peer, message })) => match message {
+                            request_response::Message::Request { request, channel, .. } => {
+                                let response = catchup_cache.missing(|offer_id| request.known.contains(offer_id));
+                                swarm.behaviour_mut().sync.send_response(channel, response).ok();
+                            },
+                            request_response::Message::Response { response, .. } => {
+                                debug!("Synced {} offers from {}", response.messages.len(), peer);
+                                validate_offers(response.messages, peer, &topic, &message_ids, &mut catchup_messages, &validation_queue);
+                            },
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Sync(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                            debug!("Syncing with {} failed: {}", peer, error);
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Stem(request_response::Event::Message { peer, message })) => match message {
+                            request_response::Message::Request { request, channel, .. } => {
+                                swarm.behaviour_mut().stem.send_response(channel, ()).ok();
+
+                                let job = ValidationJob {
+                                    message_id: message_ids.content_addressed_id(&topic.hash(), &request.envelope),
+                                    propagation_source: peer,
+                                    topic: topic.hash(),
+                                    topic_kind: TopicKind::Offers,
+                                    origin: Origin::Stem,
+                                    data: request.envelope,
+                                    received_at: SystemTime::now(),
+                                };
+
+                                if validation_queue.try_send(job).is_err() {
+                                    warn!("Validation queue full, dropping offer relayed by {}", peer);
                                 }
+                            },
+                            request_response::Message::Response { request_id, .. } => {
+                                stem_requests.remove(&request_id);
+                            },
+                        },
+                        // Fluff offers the stem broke on, the successor may not support stems
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Stem(request_response::Event::OutboundFailure { peer, request_id, error, .. })) => {
+                            debug!("Relaying offer to {} failed: {}", peer, error);
+                            stem.remove_successor(&peer);
+                            if let Some(offer_id) = stem_requests.remove(&request_id) {
+                                stem.fluff(&offer_id);
                             }
                         },
-                        SwarmEvent::Behaviour(SplashBehaviourEvent::Identify(identify::Event::Received { info: identify::Info { observed_addr, listen_addrs, .. }, peer_id, connection_id: _ })) => {
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Kademlia(kad::Event::InboundRequest { request: kad::InboundRequest::PutRecord { source, record: Some(record), .. } })) => {
+                            match records::validate_record(&network_name, record) {
+                                Ok(record) => {
+                                    swarm.behaviour_mut().kademlia.store_mut().put(record).ok();
+                                },
+                                Err(e) => debug!("Ignoring offer record from {}: {}", source, e),
+                            }
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result: kad::QueryResult::GetRecord(result), .. })) => match result {
+                            Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord { peer, record })) => match records::validate_record(&network_name, record) {
+                                Ok(record) => {
+                                    if let Some(reply) = offer_lookups.remove(&id) {
+                                        reply.send(Ok(Some(Bytes::from(record.value)))).ok();
+                                    }
+                                    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                                        query.finish();
+                                    }
+                                },
+                                Err(e) => debug!("Ignoring offer record from {:?}: {}", peer, e),
+                            },
+                            // The lookup finished without a valid record
+                            _ => {
+                                if let Some(reply) = offer_lookups.remove(&id) {
+                                    reply.send(Ok(None)).ok();
+                                }
+                            },
+                        },
+                        SwarmEvent::Behaviour(SplashBehaviourEvent::Identify(identify::Event::Received { info: identify::Info { observed_addr, mut listen_addrs, .. }, peer_id, connection_id: _ })) => {
+                            // Addresses are dialed in the order they were added, prefer QUIC
+                            listen_addrs.sort_by_key(|addr| !is_quic(addr));
                             for addr in listen_addrs {
                                 // If the node is advertising a non-global address, ignore it
                                 // TODO: also filter out ipv6 private addresses when rust API is finalized
@@ -305,7 +1337,10 @@ impl Splash {
                             // Mark the address observed for us by the external peer as confirmed.
                             // TODO: We shouldn't trust this, instead we should confirm our own address manually or using
                             // `libp2p-autonat`.
-                            swarm.add_external_address(observed_addr);
+                            // Behind a proxy, peers observe the proxy and we can't be dialed anyway
+                            if !proxied {
+                                swarm.add_external_address(observed_addr);
+                            }
                         },
                         SwarmEvent::NewListenAddr { address, .. } => {
                             event_tx.send(SplashEvent::NewListenAddress(address)).await.ok();
@@ -322,3 +1357,189 @@ impl Splash {
         })
     }
 }
+
+fn asset_topic(network_name: &str, asset: &Asset) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("/{}/assets/{}/1", network_name, asset))
+}
+
+fn is_quic(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| protocol == Protocol::QuicV1)
+}
+
+/// Publishes `data` on `topic` and the topics of `assets`, fails if it wasn't published on any
+fn publish(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: &gossipsub::IdentTopic,
+    network_name: &str,
+    assets: &[Asset],
+    data: Vec<u8>,
+) -> Result<(), gossipsub::PublishError> {
+    let mut published = gossipsub.publish(topic.clone(), data.clone()).map(|_| ());
+
+    for asset in assets {
+        let asset_published = gossipsub
+            .publish(asset_topic(network_name, asset), data.clone())
+            .map(|_| ());
+        published = published.or(asset_published);
+    }
+
+    published
+}
+
+/// Publishes an offer at the end of its stem, fails if it wasn't published on any topic. It's only
+/// published on the legacy topic while legacy nodes are connected.
+fn fluff(
+    gossipsub: &mut gossipsub::Behaviour,
+    topic: &gossipsub::IdentTopic,
+    legacy_topic: &gossipsub::IdentTopic,
+    network_name: &str,
+    offer_encoding: OfferEncoding,
+    offer: &StemOffer,
+) -> Result<(), gossipsub::PublishError> {
+    let envelope = match offer_encoding {
+        OfferEncoding::Binary => Envelope::binary_offer(offer.compressed.clone()),
+        OfferEncoding::Bech32 => Envelope::offer(offer.offer.clone()),
+    };
+    let published = publish(
+        gossipsub,
+        topic,
+        network_name,
+        &offer.assets,
+        envelope.to_bytes(),
+    );
+
+    // Nodes next to legacy nodes bridge the offer to them
+    if !has_legacy_peers(gossipsub, topic, legacy_topic) {
+        return published;
+    }
+    let legacy_published = gossipsub
+        .publish(legacy_topic.clone(), offer.offer.clone())
+        .map(|_| ());
+
+    published.or(legacy_published)
+}
+
+/// Whether a peer is subscribed to the legacy topic only, such peers don't understand envelopes
+fn has_legacy_peers(
+    gossipsub: &gossipsub::Behaviour,
+    topic: &gossipsub::IdentTopic,
+    legacy_topic: &gossipsub::IdentTopic,
+) -> bool {
+    let (topic, legacy_topic) = (topic.hash(), legacy_topic.hash());
+
+    gossipsub
+        .all_peers()
+        .any(|(_, topics)| topics.contains(&&legacy_topic) && !topics.contains(&&topic))
+}
+
+/// Hands out an offer we published to peers catching up and looking it up, once it left the stem
+fn share_offer(
+    catchup_cache: &mut RecentOffers,
+    kademlia: &mut kad::Behaviour<kad::store::MemoryStore>,
+    network_name: &str,
+    offer_records: bool,
+    offer: &StemOffer,
+) {
+    catchup_cache.insert(
+        offer.offer_id,
+        offer.compressed.clone(),
+        SystemTime::now(),
+        None,
+    );
+
+    if offer_records && offer.compressed.len() <= records::MAX_RECORD_SIZE {
+        let record = records::offer_record(
+            network_name,
+            &offer.offer_id,
+            &offer.compressed,
+            offer.expires_at,
+        );
+        if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+            warn!(
+                "Failed to publish record of offer {}: {}",
+                offer.offer_id, e
+            );
+        }
+    }
+}
+
+/// Queues offers received while catching up or syncing for validation, skipping offers other peers
+/// already sent us. A batch can be larger than the queue, so it's queued from a separate task that
+/// waits for room instead of blocking the swarm.
+fn validate_offers(
+    messages: Vec<Bytes>,
+    peer: PeerId,
+    topic: &gossipsub::IdentTopic,
+    message_ids: &MessageIds,
+    seen: &mut LruCache<gossipsub::MessageId, ()>,
+    validation_queue: &mpsc::Sender<ValidationJob>,
+) {
+    let jobs: Vec<_> = messages
+        .into_iter()
+        .filter_map(|data| {
+            let message_id = message_ids.content_addressed_id(&topic.hash(), &data);
+            seen.put(message_id.clone(), ())
+                .is_none()
+                .then(|| ValidationJob {
+                    message_id,
+                    propagation_source: peer,
+                    topic: topic.hash(),
+                    topic_kind: TopicKind::Offers,
+                    origin: Origin::Peer,
+                    data,
+                    received_at: SystemTime::now(),
+                })
+        })
+        .collect();
+
+    let validation_queue = validation_queue.clone();
+    tokio::spawn(async move {
+        for job in jobs {
+            if validation_queue.send(job).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// A new seed for every sync request, so each round has different false positives
+fn sync_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_application_topics_off_offer_topics() {
+        for node in [Splash::new(), Splash::new().with_testnet()] {
+            for prefix in ["messages", "assets"] {
+                let topic = format!("/{}/{}/1", node.network_name, prefix);
+                assert!(matches!(
+                    node.application_topic(&topic),
+                    Err(SplashError::ReservedTopic(reserved)) if reserved == topic
+                ));
+            }
+        }
+
+        // Offer topics of other networks and anything else are up to applications
+        let node = Splash::new().with_testnet();
+        for topic in [
+            "/splash/messages/2",
+            "/splash/assets/xch/1",
+            "/splash-testnet/messages",
+            "/splash-testnet/orders/1",
+            "splash-testnet/messages/2",
+            "chat",
+        ] {
+            assert_eq!(
+                node.application_topic(topic).unwrap().hash(),
+                gossipsub::IdentTopic::new(topic).hash()
+            );
+        }
+    }
+}
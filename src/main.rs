@@ -1,17 +1,30 @@
-use clap::Parser;
+use archive::{Archive, ArchivedOffer};
+use clap::{Parser, Subcommand};
 use libp2p::identity;
 use libp2p::Multiaddr;
 use serde_json::json;
-use splash::{Splash, SplashContext, SplashEvent};
+use splash::{
+    Asset, Authorship, CoinStateValidator, FullNodeRpc, OfferId, Splash, SplashContext, SplashEvent,
+};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
 use warp::http::StatusCode;
 use warp::Filter;
+mod archive;
 mod metrics;
 mod utils;
 
+// Enough for the offers of a busy hour
+const OFFER_CACHE_CAPACITY: usize = 10_000;
+
 #[derive(Parser, Debug)]
 #[clap(name = "Splash!", version = env!("CARGO_PKG_VERSION"))]
 struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(
         long,
         short,
@@ -24,10 +37,18 @@ struct Opt {
         long,
         short,
         value_name = "MULTIADDR",
-        help = "Set listen address, defaults to all interfaces, use multiple times for multiple addresses"
+        help = "Set listen address, defaults to TCP and QUIC on all interfaces, end it with `/ws` for WebSocket behind a TLS terminating proxy, use multiple times for multiple addresses"
     )]
     listen_address: Vec<Multiaddr>,
 
+    #[clap(
+        long,
+        value_name = "HOST:PORT",
+        conflicts_with = "listen_address",
+        help = "Dial all peers through a SOCKS5 proxy, e.g. Tor at 127.0.0.1:9050, which can also reach /onion3 addresses. The node doesn't listen for connections then"
+    )]
+    socks5_proxy: Option<String>,
+
     #[clap(
         long,
         short,
@@ -40,7 +61,68 @@ struct Opt {
 
     #[clap(
         long,
-        help = "HTTP endpoint where incoming messages are posted to, sends JSON body {\"message\":\"offer1...\"} (defaults to STDOUT)"
+        value_name = "MODE",
+        value_parser = ["signed", "transitional", "anonymous"],
+        default_value = "transitional",
+        help = "Publish messages signed with a throwaway key, or anonymously while still checking (transitional) or ignoring (anonymous) the signatures of peers"
+    )]
+    authorship: String,
+
+    #[clap(
+        long,
+        value_name = "ASSET",
+        help = "Only receive offers involving this asset (xch or a CAT asset ID), use multiple times for multiple assets"
+    )]
+    asset: Vec<Asset>,
+
+    #[clap(
+        long,
+        value_name = "MINUTES",
+        value_parser = clap::value_parser!(u64).range(1..=60),
+        help = "Ask the first connected peers for offers they received in the last MINUTES minutes (at most 60)"
+    )]
+    catch_up: Option<u64>,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        help = "How often to sync recent offers with a peer to recover offers gossip lost, 0 disables it"
+    )]
+    sync_interval: u64,
+
+    #[clap(
+        long,
+        help = "Publish the offers we broadcast as DHT records, so nodes that missed them can look them up"
+    )]
+    publish_offer_records: bool,
+
+    #[clap(
+        long,
+        help = "Gossip the offers we broadcast right away instead of relaying them along a random path of peers first"
+    )]
+    no_stem_phase: bool,
+
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 0,
+        help = "Hold offers we broadcast for a random time of up to SECONDS seconds and send them along with offers we relay the same way, so their timing doesn't give away that they are ours"
+    )]
+    broadcast_delay: u64,
+
+    #[clap(
+        long,
+        value_name = "UNIX_TIME",
+        default_value_t = 1_798_761_600,
+        help = "Switch to content addressed gossipsub message IDs at this time (2027-01-01 by default), all nodes of a network have to switch at the same time"
+    )]
+    content_addressed_ids_from: u64,
+
+    #[clap(
+        long,
+        global = true,
+        help = "HTTP endpoint where incoming messages are posted to, sends JSON body {\"offer\":\"offer1...\",\"offer_id\":\"...\"} (defaults to STDOUT)"
     )]
     message_hook: Option<String>,
 
@@ -51,8 +133,85 @@ struct Opt {
     )]
     listen_message_submission: Option<String>,
 
+    #[clap(
+        long,
+        help = "Keep recently received offers and start a HTTP API to query them, GET /offers?since=<UNIX_TIME> and GET /offers/<OFFER_ID>",
+        value_name = "HOST:PORT"
+    )]
+    listen_offer_cache: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "MINUTES",
+        default_value_t = 60,
+        requires = "listen_offer_cache",
+        help = "How long offers are kept in the offer cache, unless they expire, are taken or cancelled earlier"
+    )]
+    offer_cache_ttl: u64,
+
     #[clap(long, help = "Start a HTTP API for metrics", value_name = "HOST:PORT")]
     listen_metrics: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "URL",
+        help = "Drop offers spending coins that are spent according to this Chia full node RPC, e.g. https://localhost:8555"
+    )]
+    full_node_rpc: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        requires = "full_node_key",
+        help = "Client certificate for the full node RPC, usually config/ssl/full_node/private_full_node.crt"
+    )]
+    full_node_cert: Option<String>,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        requires = "full_node_cert",
+        help = "Client key for the full node RPC, usually config/ssl/full_node/private_full_node.key"
+    )]
+    full_node_key: Option<String>,
+
+    #[clap(
+        long,
+        requires = "full_node_cert",
+        help = "Accept the full node's self-signed server certificate without verifying it, Chia full nodes use one by default. Only use it for nodes on a trusted network"
+    )]
+    full_node_self_signed: bool,
+
+    #[clap(
+        long,
+        requires = "full_node_rpc",
+        help = "Watch the coins of received offers through the full node RPC and announce taken offers to the network"
+    )]
+    announce_taken_offers: bool,
+
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Append received offers to a gzip compressed JSONL file per day (UTC) in this directory"
+    )]
+    archive_dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Feed archived offers to the message hook and STDOUT, e.g. to backfill an indexer
+    Replay {
+        #[clap(required = true, value_name = "FILE", help = "Archive files, in order")]
+        files: Vec<PathBuf>,
+
+        #[clap(
+            long,
+            value_name = "FACTOR",
+            default_value_t = 1.0,
+            help = "Replay this many times faster than the offers were received, 0 replays as fast as possible"
+        )]
+        speed: f64,
+    },
 }
 
 #[tokio::main]
@@ -63,6 +222,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Welcome to Splash! v{}", env!("CARGO_PKG_VERSION"));
 
+    if let Some(Command::Replay { files, speed }) = opt.command {
+        return replay(&files, speed, opt.message_hook.as_deref()).await;
+    }
+
     let mut splash = Splash::new()
         .with_listen_addresses(opt.listen_address)
         .with_known_peers(opt.known_peer);
@@ -83,10 +246,154 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         splash = splash.with_testnet();
     }
 
+    splash = splash.with_authorship(match opt.authorship.as_str() {
+        "signed" => Authorship::Signed,
+        "anonymous" => Authorship::Anonymous,
+        _ => Authorship::Transitional,
+    });
+
+    if !opt.asset.is_empty() {
+        splash = splash.with_asset_subscriptions(opt.asset);
+    }
+
+    let sync_interval = Duration::from_secs(opt.sync_interval);
+    splash = splash.with_sync_interval(Some(sync_interval).filter(|interval| !interval.is_zero()));
+
+    if opt.publish_offer_records {
+        splash = splash.with_offer_records();
+    }
+
+    if let Some(socks5_proxy_str) = opt.socks5_proxy {
+        let socks5_proxy: SocketAddr = socks5_proxy_str.parse()?;
+        splash = splash.with_socks5_proxy(socks5_proxy);
+    }
+
+    splash = splash.with_stem_phase(!opt.no_stem_phase);
+
+    if opt.broadcast_delay > 0 {
+        splash = splash.with_broadcast_delay(Duration::from_secs(opt.broadcast_delay));
+    }
+
+    let content_addressed_ids_from = UNIX_EPOCH
+        .checked_add(Duration::from_secs(opt.content_addressed_ids_from))
+        .ok_or("Invalid --content-addressed-ids-from")?;
+    splash = splash.with_content_addressed_ids_from(content_addressed_ids_from);
+
+    if let Some(minutes) = opt.catch_up {
+        splash = splash.with_catchup(Duration::from_secs(minutes * 60));
+    }
+
+    // Check the coin state of received offers, only if --full-node-rpc is specified
+    if let Some(url) = opt.full_node_rpc {
+        let rpc = match (opt.full_node_cert, opt.full_node_key) {
+            (Some(cert), Some(key)) => FullNodeRpc::with_client_certificate(
+                url,
+                &std::fs::read(cert)?,
+                &std::fs::read(key)?,
+                opt.full_node_self_signed,
+            )?,
+            _ => FullNodeRpc::new(url),
+        };
+        splash = splash.with_validator(CoinStateValidator::new(rpc.clone()));
+
+        if opt.announce_taken_offers {
+            splash = splash.with_offer_taken_notices(rpc);
+        }
+    }
+
+    if opt.listen_offer_cache.is_some() {
+        splash = splash.with_offer_cache(
+            OFFER_CACHE_CAPACITY,
+            Duration::from_secs(opt.offer_cache_ttl * 60),
+        );
+    }
+
     let SplashContext { node, mut events } = splash.build().await?;
 
     let metrics = metrics::Metrics::new();
 
+    let mut archive = opt.archive_dir.map(Archive::new).transpose()?;
+
+    // Start a local webserver for the offer cache, only if --listen-offer-cache is specified
+    if let Some(listen_offer_cache_str) = opt.listen_offer_cache {
+        let offer_cache_addr: SocketAddr = listen_offer_cache_str.parse()?;
+
+        let offers_node = node.clone();
+        let offers_route = warp::get()
+            .and(warp::path!("offers"))
+            .and(warp::query::<utils::OffersQuery>())
+            .and_then(move |query: utils::OffersQuery| {
+                let node = offers_node.clone();
+                async move {
+                    let since = UNIX_EPOCH
+                        .checked_add(Duration::from_secs(query.since.unwrap_or_default()));
+                    let response = match since {
+                        None => warp::reply::with_status(
+                            warp::reply::json(&json!({"error": "Invalid since"})),
+                            StatusCode::BAD_REQUEST,
+                        ),
+                        Some(since) => match node.cached_offers(since).await {
+                            Ok(offers) => warp::reply::with_status(
+                                warp::reply::json(
+                                    &offers
+                                        .iter()
+                                        .map(utils::cached_offer_json)
+                                        .collect::<Vec<_>>(),
+                                ),
+                                StatusCode::OK,
+                            ),
+                            Err(e) => warp::reply::with_status(
+                                warp::reply::json(&json!({"error": e.to_string()})),
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                            ),
+                        },
+                    };
+
+                    Ok::<_, warp::Rejection>(response)
+                }
+            });
+
+        let offer_node = node.clone();
+        let offer_route =
+            warp::get()
+                .and(warp::path!("offers" / String))
+                .and_then(move |offer_id: String| {
+                    let node = offer_node.clone();
+                    async move {
+                        let mut id = [0; 32];
+                        let response = if hex::decode_to_slice(&offer_id, &mut id).is_err() {
+                            warp::reply::with_status(
+                                warp::reply::json(&json!({"error": "Invalid offer ID"})),
+                                StatusCode::BAD_REQUEST,
+                            )
+                        } else {
+                            match node.cached_offer(OfferId(id)).await {
+                                Ok(Some(offer)) => warp::reply::with_status(
+                                    warp::reply::json(&utils::cached_offer_json(&offer)),
+                                    StatusCode::OK,
+                                ),
+                                Ok(None) => warp::reply::with_status(
+                                    warp::reply::json(&json!({"error": "Offer not found"})),
+                                    StatusCode::NOT_FOUND,
+                                ),
+                                Err(e) => warp::reply::with_status(
+                                    warp::reply::json(&json!({"error": e.to_string()})),
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                ),
+                            }
+                        };
+
+                        Ok::<_, warp::Rejection>(response)
+                    }
+                });
+
+        tokio::spawn(async move {
+            warp::serve(offers_route.or(offer_route))
+                .run(offer_cache_addr)
+                .await;
+        });
+    }
+
     // Start a local webserver for message submission, only if --listen-message-submission is specified
     if let Some(message_submission_addr_str) = opt.listen_message_submission {
         let message_route =
@@ -160,19 +467,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 metrics.increment_messages_broadcasted();
             }
 
+            SplashEvent::CancellationBroadcasted(offer_id) => {
+                println!("Broadcasted cancellation of offer {}", offer_id)
+            }
+
+            SplashEvent::OfferCancelled(cancellation) => println!(
+                "Offer {} cancelled, announced by {}",
+                cancellation.offer_id, cancellation.propagation_source
+            ),
+
+            SplashEvent::OfferTaken(taken) => match taken.propagation_source {
+                Some(peer_id) => println!(
+                    "Offer {} taken at height {}, announced by {}",
+                    taken.offer_id, taken.spent_height, peer_id
+                ),
+                None => println!(
+                    "Offer {} taken at height {}",
+                    taken.offer_id, taken.spent_height
+                ),
+            },
+
+            SplashEvent::ApplicationMessage(message) => println!(
+                "Received {} bytes on {} from {}",
+                message.data.len(),
+                message.topic,
+                message.propagation_source
+            ),
+
             SplashEvent::MessageBroadcastFailed(err) => {
                 println!("Broadcasting Message failed: {}", err)
             }
 
-            SplashEvent::MessageReceived(message) => {
-                println!("Received Message: {}", message);
+            SplashEvent::MessageReceived(received) => {
+                println!(
+                    "Received Message {} from {}: {}",
+                    received.offer_id,
+                    received.propagation_source,
+                    received.to_bech32()
+                );
                 metrics.increment_messages_received();
 
+                if let Some(archive) = archive.as_mut() {
+                    if let Err(e) = archive.append(&ArchivedOffer::new(&received)) {
+                        eprintln!("Error writing to archive: {}", e);
+                    }
+                }
+
                 if let Some(ref endpoint_url) = opt.message_hook {
                     let endpoint_url_clone = endpoint_url.clone();
+                    let offer_json = utils::offer_json(&received);
                     tokio::spawn(async move {
                         if let Err(e) =
-                            utils::message_post_hook(&endpoint_url_clone, &message).await
+                            utils::message_post_hook(&endpoint_url_clone, &offer_json).await
                         {
                             eprintln!("Error posting to message hook: {}", e);
                         }
@@ -184,3 +530,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Feeds archived offers to STDOUT and the message hook, keeping the time between them divided by
+/// `speed`
+async fn replay(
+    files: &[PathBuf],
+    speed: f64,
+    message_hook: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut previous: Option<SystemTime> = None;
+
+    for path in files {
+        for archived in archive::read(path)? {
+            let archived = match archived {
+                Ok(archived) => archived,
+                Err(e) => {
+                    eprintln!("Error reading archive {}: {}", path.display(), e);
+                    break;
+                }
+            };
+
+            if let Some(previous) = previous.filter(|_| speed > 0.0) {
+                let delay = archived
+                    .received_at()
+                    .duration_since(previous)
+                    .unwrap_or_default();
+                time::sleep(delay.div_f64(speed)).await;
+            }
+            previous = Some(archived.received_at());
+
+            println!(
+                "Received Message {} from {}: {}",
+                archived.offer["offer_id"].as_str().unwrap_or_default(),
+                archived.source,
+                archived.offer["offer"].as_str().unwrap_or_default()
+            );
+
+            if let Some(endpoint_url) = message_hook {
+                if let Err(e) = utils::message_post_hook(endpoint_url, &archived.offer).await {
+                    eprintln!("Error posting to message hook: {}", e);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::envelope::{self, MessageType};
+use crate::offer::{self, Offer, OfferId};
+use libp2p::gossipsub::{self, TopicHash};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+// Content addressed message IDs are used from 2027-01-01 00:00:00 UTC onwards by default. Until
+// then nodes keep using the legacy IDs so that updated and outdated nodes agree on message identity.
+pub(crate) const CONTENT_ADDRESSED_IDS_ACTIVATION: Duration = Duration::from_secs(1_798_761_600);
+
+// Offers inflate to less than 10 times their compressed size, payloads inflating further are
+// identified by their hash instead, bounding the work per received byte
+const MAX_EXPANSION: usize = 64;
+
+// Every peer in the mesh sends us the same messages, only decompress them once
+const OFFER_ID_CACHE_SIZE: usize = 4096;
+
+/// Gossipsub message IDs, switching to content addressed IDs at the activation time
+pub(crate) struct MessageIds {
+    activation: SystemTime,
+    // Offer IDs by hash of the payload, `None` if it's not an offer
+    offer_ids: Mutex<LruCache<[u8; 32], Option<OfferId>>>,
+}
+
+impl MessageIds {
+    pub fn new(activation: SystemTime) -> Self {
+        MessageIds {
+            activation,
+            offer_ids: Mutex::new(LruCache::new(
+                NonZeroUsize::new(OFFER_ID_CACHE_SIZE).expect("cache size is not zero"),
+            )),
+        }
+    }
+
+    /// Message ID function for gossipsub
+    pub fn message_id(&self, message: &gossipsub::Message) -> gossipsub::MessageId {
+        self.message_id_at(message, SystemTime::now())
+    }
+
+    fn message_id_at(&self, message: &gossipsub::Message, now: SystemTime) -> gossipsub::MessageId {
+        if now >= self.activation {
+            self.content_addressed_id(&message.topic, &message.data)
+        } else {
+            legacy_id(&message.data)
+        }
+    }
+
+    /// The offer ID for legacy messages. Enveloped messages hash the topic together with the offer
+    /// ID, so the same offer can be published on several topics. Payloads that aren't offers are
+    /// identified by their SHA-256 hash.
+    pub fn content_addressed_id(&self, topic: &TopicHash, data: &[u8]) -> gossipsub::MessageId {
+        let hash = if envelope::is_legacy(data) {
+            self.payload_hash(MessageType::Offer, data)
+        } else {
+            let payload_hash = envelope::parse(data)
+                .map(|(envelope, payload)| self.payload_hash(envelope.message_type, payload))
+                .unwrap_or_else(|_| Sha256::digest(data).into());
+
+            Sha256::new()
+                .chain_update(topic.as_str())
+                .chain_update(payload_hash)
+                .finalize()
+                .into()
+        };
+
+        gossipsub::MessageId::from(hex::encode(hash))
+    }
+
+    fn payload_hash(&self, message_type: MessageType, data: &[u8]) -> [u8; 32] {
+        let hash: [u8; 32] = Sha256::digest(data).into();
+
+        let compressed = match message_type {
+            MessageType::Offer => std::str::from_utf8(data)
+                .ok()
+                .and_then(|message| offer::decode_bech32(message).ok())
+                .map(Cow::Owned),
+            MessageType::BinaryOffer => Some(Cow::Borrowed(data)),
+            MessageType::OfferCancellation | MessageType::OfferTaken => None,
+        };
+        let Some(compressed) = compressed else {
+            return hash;
+        };
+
+        let cached = self
+            .offer_ids
+            .lock()
+            .expect("not poisoned")
+            .get(&hash)
+            .copied();
+        let offer_id = cached.unwrap_or_else(|| {
+            let offer_id =
+                Offer::id_from_compressed_with_limit(&compressed, compressed.len() * MAX_EXPANSION)
+                    .ok();
+            self.offer_ids
+                .lock()
+                .expect("not poisoned")
+                .put(hash, offer_id);
+            offer_id
+        });
+
+        offer_id.map(|offer_id| offer_id.0).unwrap_or(hash)
+    }
+}
+
+// DefaultHasher output is not guaranteed to be stable across Rust versions
+fn legacy_id(data: &[u8]) -> gossipsub::MessageId {
+    let mut s = DefaultHasher::new();
+    data.hash(&mut s);
+    gossipsub::MessageId::from(s.finish().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, Envelope};
+    use bytes::Bytes;
+    use libp2p::PeerId;
+    use std::time::UNIX_EPOCH;
+
+    const TOPIC: &str = "/splash/messages/2";
+
+    const ACTIVATION: Duration = CONTENT_ADDRESSED_IDS_ACTIVATION;
+
+    fn id(message_ids: &MessageIds, data: &[u8]) -> String {
+        let message_id = message_ids.content_addressed_id(&TopicHash::from_raw(TOPIC), data);
+        String::from_utf8(message_id.0).unwrap()
+    }
+
+    fn message_ids() -> MessageIds {
+        MessageIds::new(UNIX_EPOCH + ACTIVATION)
+    }
+
+    fn sha256(data: &[&[u8]]) -> [u8; 32] {
+        data.iter()
+            .fold(Sha256::new(), |hasher, data| hasher.chain_update(data))
+            .finalize()
+            .into()
+    }
+
+    #[test]
+    fn legacy_offers_are_identified_by_their_offer_id() {
+        for (_, offer) in fixtures::XCH_FOR_CAT_VERSIONS {
+            assert_eq!(
+                id(&message_ids(), offer.as_bytes()),
+                "0e496b01d9a6c3be92e69ff12bc70dea564d9415e39c39c5f8b3e4bb1d499ed9"
+            );
+        }
+    }
+
+    #[test]
+    fn enveloped_offers_are_identified_by_topic_and_offer_id() {
+        let message_ids = message_ids();
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let expected = "ca3e4f879eeaa99b81a259f57741ca4f0e69e91ab03391a57a930d9c84161ab0";
+
+        let mut envelope = Envelope::binary_offer(offer.compressed().clone());
+        assert_eq!(id(&message_ids, &envelope.to_bytes()), expected);
+
+        // Neither the timestamp nor the encoding change the ID
+        envelope.timestamp = None;
+        assert_eq!(id(&message_ids, &envelope.to_bytes()), expected);
+        let envelope = Envelope::offer(Bytes::from_static(fixtures::XCH_FOR_CAT.as_bytes()));
+        assert_eq!(id(&message_ids, &envelope.to_bytes()), expected);
+
+        // The same offer on another topic is another message
+        let asset_topic = TopicHash::from_raw("/splash/assets/xch/1");
+        assert_ne!(
+            message_ids.content_addressed_id(&asset_topic, &envelope.to_bytes()),
+            gossipsub::MessageId::from(expected)
+        );
+    }
+
+    #[test]
+    fn other_payloads_are_hashed() {
+        let message_ids = message_ids();
+
+        // Not an offer, the payload is hashed
+        let envelope = Envelope::binary_offer(Bytes::from_static(b"not an offer"));
+        assert_eq!(
+            id(&message_ids, &envelope.to_bytes()),
+            hex::encode(sha256(&[TOPIC.as_bytes(), &sha256(&[b"not an offer"])]))
+        );
+
+        // Not an envelope, the message is hashed
+        assert_eq!(
+            id(&message_ids, b"\x07garbage"),
+            hex::encode(sha256(&[TOPIC.as_bytes(), &sha256(&[b"\x07garbage"])]))
+        );
+    }
+
+    #[test]
+    fn payloads_inflating_too_much_are_hashed() {
+        let bomb = fixtures::compress(6, &[0; 1024 * 1024]);
+        let envelope = Envelope::binary_offer(bomb.clone());
+
+        assert_eq!(
+            id(&message_ids(), &envelope.to_bytes()),
+            hex::encode(sha256(&[TOPIC.as_bytes(), &sha256(&[&bomb])]))
+        );
+    }
+
+    #[test]
+    fn offer_ids_are_cached() {
+        let message_ids = message_ids();
+        let data = fixtures::XCH_FOR_CAT.as_bytes();
+
+        let first = id(&message_ids, data);
+        assert_eq!(message_ids.offer_ids.lock().unwrap().len(), 1);
+
+        assert_eq!(id(&message_ids, data), first);
+        assert_eq!(message_ids.offer_ids.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn switches_to_content_addressed_ids_at_activation() {
+        let message_ids = message_ids();
+        let message = gossipsub::Message {
+            source: Some(PeerId::random()),
+            data: fixtures::XCH_FOR_CAT.as_bytes().to_vec(),
+            sequence_number: Some(1),
+            topic: TopicHash::from_raw(TOPIC),
+        };
+        let offer_id = gossipsub::MessageId::from(
+            "0e496b01d9a6c3be92e69ff12bc70dea564d9415e39c39c5f8b3e4bb1d499ed9",
+        );
+
+        let before =
+            message_ids.message_id_at(&message, UNIX_EPOCH + ACTIVATION - Duration::from_secs(1));
+        assert_eq!(before, legacy_id(&message.data));
+
+        for now in [
+            UNIX_EPOCH + ACTIVATION,
+            UNIX_EPOCH + ACTIVATION + Duration::from_secs(1),
+        ] {
+            assert_eq!(message_ids.message_id_at(&message, now), offer_id);
+        }
+
+        // The activation is configurable
+        let early = MessageIds::new(UNIX_EPOCH);
+        assert_eq!(early.message_id(&message), offer_id);
+    }
+}
@@ -0,0 +1,673 @@
+use crate::assets::{self, Asset};
+use crate::conditions::{self, Condition};
+use crate::{puzzles, SplashError};
+use bech32::{FromBase32, ToBase32, Variant};
+use bytes::Bytes;
+use flate2::{Decompress, FlushDecompress, Status};
+use log::debug;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const OFFER_PREFIX: &str = "offer";
+
+// Same limit the Chia wallet applies when inflating offers
+const MAX_DECOMPRESSED_SIZE: usize = 6 * 1024 * 1024;
+
+const G2_ELEMENT_SIZE: usize = 96;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coin {
+    pub parent_coin_info: [u8; 32],
+    pub puzzle_hash: [u8; 32],
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSpend {
+    pub coin: Coin,
+    pub puzzle_reveal: Vec<u8>,
+    pub solution: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendBundle {
+    pub coin_spends: Vec<CoinSpend>,
+    pub aggregated_signature: [u8; G2_ELEMENT_SIZE],
+}
+
+/// Canonical offer ID, the hash of the offer's serialized spend bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OfferId(pub [u8; 32]);
+
+impl fmt::Display for OfferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// A decoded Chia offer
+#[derive(Debug, Clone)]
+pub struct Offer {
+    id: OfferId,
+    // Version header followed by the zlib compressed spend bundle, what the bech32 string encodes
+    compressed: Bytes,
+    spend_bundle: SpendBundle,
+    // Conditions of each coin spend, `None` if a puzzle failed
+    conditions: Option<Vec<Vec<Condition>>>,
+}
+
+impl Offer {
+    /// Decodes an `offer1...` string the same way the Chia wallet does: bech32m decode,
+    /// inflate with the puzzle dictionary selected by the version header, then parse
+    /// the result as a streamable `SpendBundle`. The maker's coin spends are run to collect
+    /// their conditions, if a puzzle fails the offer has no known conditions.
+    pub fn decode(offer: &str) -> Result<Offer, SplashError> {
+        Offer::from_compressed(decode_bech32(offer)?.into())
+    }
+
+    /// Decodes the compressed bytes of an offer, see [`Offer::decode`]
+    pub fn from_compressed(compressed: Bytes) -> Result<Offer, SplashError> {
+        let bytes = decompress_offer(&compressed)?;
+        let spend_bundle = SpendBundle::from_bytes(&bytes)?;
+
+        if spend_bundle.coin_spends.is_empty() {
+            return Err(SplashError::InvalidSpendBundle(
+                "offer has no coin spends".to_string(),
+            ));
+        }
+
+        let id = OfferId(Sha256::digest(&bytes).into());
+
+        // Requested payments are spends of settlement coins that don't exist yet, they only
+        // carry the notarized payments and their puzzles aren't meant to be run on their own
+        let conditions = match spend_bundle
+            .coin_spends
+            .iter()
+            .map(|coin_spend| {
+                if coin_spend.coin.parent_coin_info == [0; 32] {
+                    Ok(Vec::new())
+                } else {
+                    coin_spend.conditions()
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(conditions) => Some(conditions),
+            Err(e) => {
+                debug!(
+                    "Offer {} can't be run, skipping condition checks: {}",
+                    id, e
+                );
+                None
+            }
+        };
+
+        Ok(Offer {
+            id,
+            compressed,
+            spend_bundle,
+            conditions,
+        })
+    }
+
+    /// Computes the offer ID without parsing or running the spend bundle
+    pub fn decode_id(offer: &str) -> Result<OfferId, SplashError> {
+        Offer::id_from_compressed(&decode_bech32(offer)?)
+    }
+
+    /// Computes the offer ID of compressed offer bytes, see [`Offer::decode_id`]
+    pub fn id_from_compressed(compressed: &[u8]) -> Result<OfferId, SplashError> {
+        Offer::id_from_compressed_with_limit(compressed, MAX_DECOMPRESSED_SIZE)
+    }
+
+    /// Computes the offer ID of compressed offer bytes, fails if they inflate to more than
+    /// `max_size` bytes
+    pub(crate) fn id_from_compressed_with_limit(
+        compressed: &[u8],
+        max_size: usize,
+    ) -> Result<OfferId, SplashError> {
+        let bytes = decompress_offer_with_limit(compressed, max_size)?;
+        Ok(OfferId(Sha256::digest(&bytes).into()))
+    }
+
+    pub fn id(&self) -> OfferId {
+        self.id
+    }
+
+    pub fn spend_bundle(&self) -> &SpendBundle {
+        &self.spend_bundle
+    }
+
+    /// IDs of the maker's coins spent by the offer. Settlement coins are created by the offer
+    /// itself and have no parent yet, they are left out.
+    pub fn coin_ids(&self) -> Vec<[u8; 32]> {
+        self.spend_bundle
+            .coin_spends
+            .iter()
+            .filter(|coin_spend| coin_spend.coin.parent_coin_info != [0; 32])
+            .map(|coin_spend| coin_spend.coin.coin_id())
+            .collect()
+    }
+
+    /// Assets offered or requested by the offer, sorted and without duplicates. NFTs and assets
+    /// with puzzles we don't know are left out.
+    pub fn assets(&self) -> Vec<Asset> {
+        assets::offer_assets(self)
+    }
+
+    /// The compressed offer bytes, about 40% smaller than the bech32 string
+    pub fn compressed(&self) -> &Bytes {
+        &self.compressed
+    }
+
+    /// Encodes the offer as `offer1...` string
+    pub fn to_bech32(&self) -> String {
+        encode_bech32(&self.compressed)
+    }
+
+    /// Conditions output by each coin spend, in the same order as the coin spends. Requested
+    /// payments have no conditions. `None` if a puzzle failed.
+    pub fn conditions(&self) -> Option<&[Vec<Condition>]> {
+        self.conditions.as_deref()
+    }
+
+    /// Earliest ASSERT_BEFORE_SECONDS_ABSOLUTE of all coin spends, the offer can't be taken
+    /// at or after this time
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.min_condition_arg(conditions::ASSERT_BEFORE_SECONDS_ABSOLUTE)
+            .and_then(|seconds| UNIX_EPOCH.checked_add(Duration::from_secs(seconds)))
+    }
+
+    /// Earliest ASSERT_BEFORE_HEIGHT_ABSOLUTE of all coin spends, the offer can't be taken
+    /// at or after this block height
+    pub fn expires_at_height(&self) -> Option<u32> {
+        self.min_condition_arg(conditions::ASSERT_BEFORE_HEIGHT_ABSOLUTE)
+            .map(|height| height.min(u32::MAX as u64) as u32)
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| now >= expires_at)
+    }
+
+    fn min_condition_arg(&self, opcode: u16) -> Option<u64> {
+        self.conditions
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|condition| condition.opcode == opcode)
+            // A negative or oversized argument can never be satisfied
+            .map(|condition| condition.u64_arg(0).unwrap_or(0))
+            .min()
+    }
+}
+
+pub(crate) fn decode_bech32(offer: &str) -> Result<Vec<u8>, SplashError> {
+    let (hrp, data, variant) =
+        bech32::decode(offer).map_err(|_| SplashError::InvalidMessageFormat)?;
+
+    if hrp != OFFER_PREFIX {
+        return Err(SplashError::InvalidOfferPrefix(hrp));
+    }
+
+    if variant != Variant::Bech32m {
+        return Err(SplashError::InvalidOfferEncoding);
+    }
+
+    Vec::<u8>::from_base32(&data).map_err(|_| SplashError::InvalidOfferEncoding)
+}
+
+/// Encodes compressed offer bytes as `offer1...` string
+pub(crate) fn encode_bech32(compressed: &[u8]) -> String {
+    bech32::encode(OFFER_PREFIX, compressed.to_base32(), Variant::Bech32m)
+        .expect("offer prefix is a valid human readable part")
+}
+
+pub(crate) fn decompress_offer(compressed: &[u8]) -> Result<Vec<u8>, SplashError> {
+    decompress_offer_with_limit(compressed, MAX_DECOMPRESSED_SIZE)
+}
+
+fn decompress_offer_with_limit(compressed: &[u8], max_size: usize) -> Result<Vec<u8>, SplashError> {
+    if compressed.len() < 2 {
+        return Err(SplashError::OfferDecompressionFailed(
+            "missing version header".to_string(),
+        ));
+    }
+
+    let version = u16::from_be_bytes([compressed[0], compressed[1]]);
+    let zdict =
+        puzzles::zdict_for_version(version).ok_or(SplashError::UnsupportedOfferVersion(version))?;
+
+    inflate_with_dictionary(
+        &compressed[2..],
+        &zdict,
+        max_size.min(MAX_DECOMPRESSED_SIZE),
+    )
+    .map_err(SplashError::OfferDecompressionFailed)
+}
+
+fn inflate_with_dictionary(input: &[u8], zdict: &[u8], max_size: usize) -> Result<Vec<u8>, String> {
+    let mut inflater = Decompress::new(true);
+    let mut output = Vec::with_capacity((input.len() * 4).min(max_size));
+
+    loop {
+        if output.len() == output.capacity() {
+            if output.len() >= max_size {
+                return Err(format!("exceeds maximum size of {} bytes", max_size));
+            }
+            let additional = output.capacity().max(1024);
+            output.reserve_exact(additional.min(max_size - output.len()));
+        }
+
+        let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+        let remaining = &input[total_in as usize..];
+
+        match inflater.decompress_vec(remaining, &mut output, FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) => return Ok(output),
+            Ok(_) => {
+                let no_progress =
+                    inflater.total_in() == total_in && inflater.total_out() == total_out;
+                if no_progress && output.len() < output.capacity() {
+                    return Err("unexpected end of stream".to_string());
+                }
+            }
+            Err(e) if e.needs_dictionary().is_some() => {
+                inflater.set_dictionary(zdict).map_err(|e| e.to_string())?;
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+impl SpendBundle {
+    /// Parses the Chia streamable serialization of a spend bundle, rejecting trailing bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SpendBundle, SplashError> {
+        let mut reader = Reader::new(bytes);
+        let spend_bundle = reader
+            .spend_bundle()
+            .map_err(SplashError::InvalidSpendBundle)?;
+
+        if !reader.is_empty() {
+            return Err(SplashError::InvalidSpendBundle(format!(
+                "{} trailing bytes",
+                reader.remaining()
+            )));
+        }
+
+        Ok(spend_bundle)
+    }
+}
+
+/// Reader for the Chia streamable format, big endian integers and length prefixed bytes
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Reader { data, position: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.remaining() < len {
+            return Err(format!(
+                "unexpected end of data at byte {}, needed {} more",
+                self.position, len
+            ));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(u8::from_be_bytes(self.array()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn optional<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            flag => Err(format!("invalid optional flag {}", flag)),
+        }
+    }
+
+    fn coin(&mut self) -> Result<Coin, String> {
+        Ok(Coin {
+            parent_coin_info: self.array()?,
+            puzzle_hash: self.array()?,
+            amount: self.u64()?,
+        })
+    }
+
+    /// Reads one serialized CLVM program without evaluating it
+    fn program(&mut self) -> Result<Vec<u8>, String> {
+        let start = self.position;
+        let mut pending = 1usize;
+
+        while pending > 0 {
+            let [byte] = self.array::<1>()?;
+            pending -= 1;
+
+            match byte {
+                0xff => pending += 2,
+                0xfe => return Err("back references are not supported".to_string()),
+                0x00..=0x80 => {}
+                _ => {
+                    // Atom with a length prefix, the number of leading ones is the prefix size
+                    let prefix_len = byte.leading_ones() as usize;
+                    let mut len = (byte & (0xff >> prefix_len)) as u64;
+                    for &b in self.take(prefix_len - 1)? {
+                        len = (len << 8) | b as u64;
+                    }
+                    let len = usize::try_from(len).map_err(|_| "atom too large".to_string())?;
+                    self.take(len)?;
+                }
+            }
+        }
+
+        Ok(self.data[start..self.position].to_vec())
+    }
+
+    fn coin_spend(&mut self) -> Result<CoinSpend, String> {
+        Ok(CoinSpend {
+            coin: self.coin()?,
+            puzzle_reveal: self.program()?,
+            solution: self.program()?,
+        })
+    }
+
+    fn spend_bundle(&mut self) -> Result<SpendBundle, String> {
+        let count = self.u32()? as usize;
+
+        // Every coin spend is at least 75 bytes, don't trust the length prefix for allocation
+        let mut coin_spends = Vec::with_capacity(count.min(self.remaining() / 75));
+        for _ in 0..count {
+            coin_spends.push(self.coin_spend()?);
+        }
+
+        Ok(SpendBundle {
+            coin_spends,
+            aggregated_signature: self.array()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use hex_literal::hex;
+
+    fn asset(asset_id: &str) -> Asset {
+        asset_id.parse().unwrap()
+    }
+
+    /// Decodes a fixture and checks the puzzles of all the maker's coins could be run
+    fn decode(offer: &str) -> Offer {
+        let offer = Offer::decode(offer).unwrap();
+        assert!(offer.conditions().is_some());
+        offer
+    }
+
+    #[test]
+    fn decodes_every_dictionary_version() {
+        for (version, encoded) in fixtures::XCH_FOR_CAT_VERSIONS {
+            let offer = Offer::decode(encoded).unwrap();
+
+            assert_eq!(offer.compressed()[..2], version.to_be_bytes());
+            assert_eq!(
+                offer.id(),
+                OfferId(hex!(
+                    "0e496b01d9a6c3be92e69ff12bc70dea564d9415e39c39c5f8b3e4bb1d499ed9"
+                ))
+            );
+            assert_eq!(Offer::decode_id(encoded).unwrap(), offer.id());
+            assert_eq!(
+                offer.coin_ids(),
+                [hex!(
+                    "53645c83e898184cb28ecf460ec144e957eba68f4c3d93d39251f7105665aed0"
+                )]
+            );
+
+            let [offered, requested] = offer.spend_bundle().coin_spends.as_slice() else {
+                panic!("expected 2 coin spends");
+            };
+            assert_eq!(
+                offered.coin,
+                Coin {
+                    parent_coin_info: hex!(
+                        "70788b9d6210d1870cda0f02887c9e282b1baafc8ddc7e1229fccd01bfd9c490"
+                    ),
+                    puzzle_hash: hex!(
+                        "f5c0afb9d48d677638ced3f94a5348cf7e8444d455caf6780798c254dd266186"
+                    ),
+                    amount: 100,
+                }
+            );
+            assert_eq!(
+                (offered.puzzle_reveal.len(), offered.solution.len()),
+                (291, 87)
+            );
+            assert_eq!(
+                requested.coin,
+                Coin {
+                    parent_coin_info: [0; 32],
+                    puzzle_hash: hex!(
+                        "f9feed7c731493b7852b367e5e52cdba6790a1503a91417e35ebd6f25ec25570"
+                    ),
+                    amount: 0,
+                }
+            );
+            assert_eq!(
+                (requested.puzzle_reveal.len(), requested.solution.len()),
+                (2060, 77)
+            );
+
+            assert_eq!(offer.to_bech32(), encoded);
+        }
+    }
+
+    #[test]
+    fn decodes_wallet_offer() {
+        let offer = Offer::decode(fixtures::NFT_FOR_CAT).unwrap();
+
+        assert_eq!(
+            offer.id(),
+            OfferId(hex!(
+                "879724252cff4424a4decc29e22b8e70f017ba80ca5a3b75030504e233cf4ca8"
+            ))
+        );
+        assert_eq!(
+            offer.coin_ids(),
+            [hex!(
+                "9ed442f9996683ea0a55397d98e08970de52b7f1019c7703c7968cd877a165b8"
+            )]
+        );
+
+        let coins: Vec<_> = offer
+            .spend_bundle()
+            .coin_spends
+            .iter()
+            .map(|coin_spend| (coin_spend.coin.parent_coin_info[0], coin_spend.coin.amount))
+            .collect();
+        assert_eq!(coins, [(0x00, 0), (0x9f, 1)]);
+    }
+
+    #[test]
+    fn rejects_invalid_encodings() {
+        let compressed = decode_bech32(fixtures::XCH_FOR_CAT).unwrap();
+        let encode = |hrp: &str, data: &[u8], variant| {
+            bech32::encode(hrp, data.to_base32(), variant).unwrap()
+        };
+
+        assert!(matches!(
+            Offer::decode(&encode("xch", &compressed, Variant::Bech32m)),
+            Err(SplashError::InvalidOfferPrefix(hrp)) if hrp == "xch"
+        ));
+        assert!(matches!(
+            Offer::decode(&encode("offer", &compressed, Variant::Bech32)),
+            Err(SplashError::InvalidOfferEncoding)
+        ));
+        assert!(matches!(
+            Offer::decode(&fixtures::XCH_FOR_CAT[..fixtures::XCH_FOR_CAT.len() - 1]),
+            Err(SplashError::InvalidMessageFormat)
+        ));
+
+        let mut unknown_version = compressed.clone();
+        unknown_version[1] = 7;
+        assert!(matches!(
+            Offer::from_compressed(unknown_version.into()),
+            Err(SplashError::UnsupportedOfferVersion(7))
+        ));
+
+        let truncated = &compressed[..compressed.len() - 10];
+        assert!(matches!(
+            Offer::from_compressed(Bytes::copy_from_slice(truncated)),
+            Err(SplashError::OfferDecompressionFailed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let mut bytes = decompress_offer(offer.compressed()).unwrap();
+        bytes.push(0);
+
+        assert!(matches!(
+            Offer::from_compressed(fixtures::compress(6, &bytes)),
+            Err(SplashError::InvalidSpendBundle(_))
+        ));
+    }
+
+    #[test]
+    fn decodes_xch_for_cat() {
+        let offer = decode(fixtures::XCH_FOR_CAT);
+
+        assert_eq!(
+            offer.assets(),
+            [
+                Asset::Xch,
+                asset("7cc7e6a7c9fb65550e8fda00764038cf706d403d8c0cf59da7dfee778f3841d1")
+            ]
+        );
+        assert_eq!(offer.expires_at(), None);
+    }
+
+    #[test]
+    fn decodes_cat_for_xch() {
+        let offer = decode(fixtures::CAT_FOR_XCH);
+
+        assert_eq!(
+            offer.assets(),
+            [
+                Asset::Xch,
+                asset("4c278e415c32758545a893ad89d9e2a772b7dcd07a633b72befc0545297d3444")
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_cat_for_cat() {
+        let offer = decode(fixtures::CAT_FOR_CAT);
+
+        assert_eq!(
+            offer.assets(),
+            [
+                asset("5d01c78f0a3a1defd668fb4a27641b00c6feb73fa51bc498e6ac57a68a9b2c7f"),
+                asset("a053156d40bf0bfd4f30ac1b4bb24c4e8f0aad0faed29f35b762ae72b8b8295d")
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_nft_offers() {
+        assert_eq!(decode(fixtures::XCH_FOR_NFT).assets(), [Asset::Xch]);
+        assert_eq!(decode(fixtures::NFT_FOR_XCH).assets(), [Asset::Xch]);
+        assert_eq!(
+            decode(fixtures::NFT_FOR_CAT).assets(),
+            [asset(
+                "02f42883fb3338310825c951efcca810ecb61772d9e5da6a2d4d0a6591b8897e"
+            )]
+        );
+    }
+
+    #[test]
+    fn requested_payments_are_not_run() {
+        let offer = decode(fixtures::NFT_FOR_CAT);
+        let coin_spends = &offer.spend_bundle().coin_spends;
+
+        let requested = coin_spends
+            .iter()
+            .position(|coin_spend| coin_spend.coin.parent_coin_info == [0; 32])
+            .unwrap();
+
+        // Only carries the notarized payments, running it on its own fails
+        assert!(coin_spends[requested].conditions().is_err());
+        assert!(offer.conditions().unwrap()[requested].is_empty());
+    }
+
+    #[test]
+    fn expiring_offer() {
+        let offer = decode(fixtures::EXPIRING);
+        let expires_at = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+
+        assert_eq!(offer.expires_at(), Some(expires_at));
+        assert!(!offer.is_expired(expires_at - Duration::from_secs(1)));
+        assert!(offer.is_expired(expires_at));
+    }
+
+    #[test]
+    fn failing_puzzle_has_no_known_expiry() {
+        // `(x)`, raises whatever the solution
+        let puzzle = hex!("ff0880");
+        let puzzle_hash = clvm_utils::tree_hash_from_bytes(&puzzle).unwrap();
+
+        let mut spend_bundle = 1u32.to_be_bytes().to_vec();
+        spend_bundle.extend_from_slice(&[1; 32]);
+        spend_bundle.extend_from_slice(&puzzle_hash.to_bytes());
+        spend_bundle.extend_from_slice(&1u64.to_be_bytes());
+        spend_bundle.extend_from_slice(&puzzle);
+        spend_bundle.push(0x80);
+        spend_bundle.extend_from_slice(&[0xc0]);
+        spend_bundle.extend_from_slice(&[0; G2_ELEMENT_SIZE - 1]);
+
+        let offer = Offer::from_compressed(fixtures::compress(6, &spend_bundle)).unwrap();
+
+        assert_eq!(offer.conditions(), None);
+        assert_eq!(offer.expires_at(), None);
+        assert!(!offer.is_expired(SystemTime::now()));
+    }
+}
@@ -0,0 +1,209 @@
+//! Offers received recently, for nodes that want to know what is live without an indexer
+
+use crate::{OfferId, ReceivedOffer};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::time::{Duration, SystemTime};
+
+/// Received offers, kept until they expire, are taken or cancelled, or `ttl` after they were
+/// first seen. Once `capacity` offers are cached the oldest ones are dropped.
+pub(crate) struct OfferCache {
+    // Offers are only inserted when first seen, so the least recently used is the oldest
+    offers: LruCache<OfferId, ReceivedOffer>,
+    ttl: Duration,
+}
+
+impl OfferCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        OfferCache {
+            offers: LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("capacity is not zero"),
+            ),
+            ttl,
+        }
+    }
+
+    /// Caches an offer unless it's cached already, keeping when and from whom we first got it
+    pub fn insert(&mut self, offer: ReceivedOffer) {
+        if self.offers.contains(&offer.offer_id) {
+            return;
+        }
+        self.offers.push(offer.offer_id, offer);
+
+        let now = SystemTime::now();
+        while self
+            .offers
+            .peek_lru()
+            .is_some_and(|(_, offer)| !self.is_live(offer, now))
+        {
+            self.offers.pop_lru();
+        }
+    }
+
+    pub fn remove(&mut self, offer_id: &OfferId) {
+        self.offers.pop(offer_id);
+    }
+
+    pub fn get(&self, offer_id: &OfferId) -> Option<ReceivedOffer> {
+        let now = SystemTime::now();
+        self.offers
+            .peek(offer_id)
+            .filter(|offer| self.is_live(offer, now))
+            .cloned()
+    }
+
+    /// Offers first seen at or after `since`, oldest first
+    pub fn since(&self, since: SystemTime) -> Vec<ReceivedOffer> {
+        let now = SystemTime::now();
+        self.offers
+            .iter()
+            .rev()
+            .map(|(_, offer)| offer)
+            .filter(|offer| offer.received_at >= since && self.is_live(offer, now))
+            .cloned()
+            .collect()
+    }
+
+    fn is_live(&self, offer: &ReceivedOffer, now: SystemTime) -> bool {
+        let fresh = now
+            .duration_since(offer.received_at)
+            .map_or(true, |age| age <= self.ttl);
+        let expired = offer.expires_at.is_some_and(|expires_at| now >= expires_at);
+
+        fresh && !expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use libp2p::gossipsub::{MessageId, TopicHash};
+    use libp2p::PeerId;
+
+    const TTL: Duration = Duration::from_secs(60 * 60);
+
+    fn offer_id(id: u8) -> OfferId {
+        OfferId([id; 32])
+    }
+
+    fn offer(id: u8, received_at: SystemTime) -> ReceivedOffer {
+        ReceivedOffer {
+            offer_id: offer_id(id),
+            compressed_offer: Bytes::new(),
+            message_id: MessageId::from(vec![id]),
+            topic: TopicHash::from_raw("/splash/messages/2"),
+            propagation_source: PeerId::random(),
+            received_at,
+            expires_at: None,
+            expires_at_height: None,
+            assets: Vec::new(),
+            message_type: None,
+            published_at: None,
+        }
+    }
+
+    fn minutes_ago(minutes: u64) -> SystemTime {
+        SystemTime::now() - Duration::from_secs(minutes * 60)
+    }
+
+    fn ids(offers: Vec<ReceivedOffer>) -> Vec<OfferId> {
+        offers.into_iter().map(|offer| offer.offer_id).collect()
+    }
+
+    #[test]
+    fn returns_offers_since_oldest_first() {
+        let mut cache = OfferCache::new(10, TTL);
+        for (id, minutes) in [(1, 30), (2, 20), (3, 10)] {
+            cache.insert(offer(id, minutes_ago(minutes)));
+        }
+
+        assert_eq!(
+            ids(cache.since(SystemTime::UNIX_EPOCH)),
+            [offer_id(1), offer_id(2), offer_id(3)]
+        );
+        assert_eq!(
+            ids(cache.since(minutes_ago(25))),
+            [offer_id(2), offer_id(3)]
+        );
+        assert!(cache.since(SystemTime::now()).is_empty());
+        assert_eq!(cache.get(&offer_id(2)).unwrap().offer_id, offer_id(2));
+        assert!(cache.get(&offer_id(4)).is_none());
+    }
+
+    #[test]
+    fn hides_offers_after_ttl() {
+        let mut cache = OfferCache::new(10, TTL);
+        cache.insert(offer(1, minutes_ago(61)));
+        cache.insert(offer(2, minutes_ago(59)));
+
+        assert!(cache.get(&offer_id(1)).is_none());
+        assert!(cache.get(&offer_id(2)).is_some());
+        assert_eq!(ids(cache.since(SystemTime::UNIX_EPOCH)), [offer_id(2)]);
+    }
+
+    #[test]
+    fn hides_expired_offers() {
+        let mut cache = OfferCache::new(10, TTL);
+        let mut expired = offer(1, minutes_ago(2));
+        expired.expires_at = Some(minutes_ago(1));
+        let mut expiring = offer(2, minutes_ago(2));
+        expiring.expires_at = Some(SystemTime::now() + Duration::from_secs(60));
+        cache.insert(expired);
+        cache.insert(expiring);
+
+        assert!(cache.get(&offer_id(1)).is_none());
+        assert!(cache.get(&offer_id(2)).is_some());
+        assert_eq!(ids(cache.since(SystemTime::UNIX_EPOCH)), [offer_id(2)]);
+    }
+
+    #[test]
+    fn drops_oldest_offers_when_full() {
+        let mut cache = OfferCache::new(2, TTL);
+        for id in 1..=3 {
+            cache.insert(offer(id, SystemTime::now()));
+        }
+
+        assert!(cache.get(&offer_id(1)).is_none());
+        assert_eq!(
+            ids(cache.since(SystemTime::UNIX_EPOCH)),
+            [offer_id(2), offer_id(3)]
+        );
+    }
+
+    #[test]
+    fn removes_offers() {
+        let mut cache = OfferCache::new(10, TTL);
+        cache.insert(offer(1, SystemTime::now()));
+        cache.insert(offer(2, SystemTime::now()));
+
+        cache.remove(&offer_id(1));
+        cache.remove(&offer_id(3));
+
+        assert!(cache.get(&offer_id(1)).is_none());
+        assert_eq!(ids(cache.since(SystemTime::UNIX_EPOCH)), [offer_id(2)]);
+    }
+
+    #[test]
+    fn keeps_when_and_from_whom_offers_were_first_seen() {
+        let mut cache = OfferCache::new(2, TTL);
+        let first = offer(1, minutes_ago(20));
+        cache.insert(first.clone());
+        cache.insert(offer(2, minutes_ago(10)));
+
+        // Seen again from another peer, e.g. while catching up
+        cache.insert(offer(1, SystemTime::now()));
+
+        let cached = cache.get(&offer_id(1)).unwrap();
+        assert_eq!(cached.received_at, first.received_at);
+        assert_eq!(cached.propagation_source, first.propagation_source);
+        assert_eq!(
+            ids(cache.since(SystemTime::UNIX_EPOCH)),
+            [offer_id(1), offer_id(2)]
+        );
+
+        // It's still the oldest offer, so it's the first one dropped
+        cache.insert(offer(3, SystemTime::now()));
+        assert!(cache.get(&offer_id(1)).is_none());
+    }
+}
@@ -0,0 +1,161 @@
+//! Offers published as Kademlia records keyed by offer ID, so nodes can look up offers they missed
+//!
+//! Records are content addressed: the key names the offer ID, which is the hash of the offer, so
+//! peers can't store anything but the named offer under a key.
+
+use crate::{Offer, OfferId, SplashError};
+use libp2p::kad;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Larger offers aren't published, most offers are a few kilobytes
+pub(crate) const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+// Same as the Kademlia default, records of peers are kept at most this long
+const RECORD_TTL: Duration = Duration::from_secs(36 * 60 * 60);
+
+pub(crate) fn record_key(network_name: &str, offer_id: &OfferId) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("/{}/offers/{}", network_name, offer_id))
+}
+
+/// The record of an offer we publish, it expires with the offer
+pub(crate) fn offer_record(
+    network_name: &str,
+    offer_id: &OfferId,
+    compressed: &[u8],
+    expires_at: Option<SystemTime>,
+) -> kad::Record {
+    let mut record = kad::Record::new(record_key(network_name, offer_id), compressed.to_vec());
+    record.expires = expires_at.map(|expires_at| {
+        Instant::now()
+            + expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+    });
+    record
+}
+
+/// Checks that a record received from a peer holds the offer its key names, the offer itself is
+/// validated when it's looked up
+pub(crate) fn validate_record(
+    network_name: &str,
+    mut record: kad::Record,
+) -> Result<kad::Record, SplashError> {
+    if record.value.len() > MAX_RECORD_SIZE {
+        return Err(SplashError::InvalidOfferRecord(format!(
+            "record of {} bytes is too large",
+            record.value.len()
+        )));
+    }
+
+    let offer_id = Offer::id_from_compressed(&record.value)?;
+    if record.key != record_key(network_name, &offer_id) {
+        return Err(SplashError::InvalidOfferRecord(format!(
+            "record doesn't hold offer {}",
+            offer_id
+        )));
+    }
+
+    // Don't let peers make us keep records forever
+    let max_expires = Instant::now() + RECORD_TTL;
+    record.expires = Some(
+        record
+            .expires
+            .map_or(max_expires, |expires| expires.min(max_expires)),
+    );
+
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn record() -> (OfferId, kad::Record) {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let record = offer_record("testnet11", &offer.id(), offer.compressed(), None);
+        (offer.id(), record)
+    }
+
+    #[test]
+    fn keys_records_by_network_and_offer_id() {
+        let (offer_id, record) = record();
+        assert_eq!(
+            record.key.as_ref(),
+            format!("/testnet11/offers/{}", offer_id).as_bytes()
+        );
+        assert_ne!(record.key, record_key("mainnet", &offer_id));
+    }
+
+    #[test]
+    fn expires_records_with_their_offers() {
+        let (offer_id, record) = record();
+        assert_eq!(record.expires, None);
+
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let record = offer_record("testnet11", &offer_id, &record.value, Some(expires_at));
+        let expires = record.expires.unwrap();
+        assert!(expires > Instant::now() + Duration::from_secs(50));
+        assert!(expires <= Instant::now() + Duration::from_secs(60));
+
+        // Expired offers make records that expire right away
+        let expires_at = SystemTime::now() - Duration::from_secs(60);
+        let record = offer_record("testnet11", &offer_id, &record.value, Some(expires_at));
+        assert!(record.expires.unwrap() <= Instant::now());
+    }
+
+    #[test]
+    fn accepts_records_holding_their_offer() {
+        let (_, record) = record();
+        let validated = validate_record("testnet11", record.clone()).unwrap();
+        assert_eq!(validated.key, record.key);
+        assert_eq!(validated.value, record.value);
+    }
+
+    #[test]
+    fn caps_how_long_records_are_kept() {
+        let (_, mut record) = record();
+        let validated = validate_record("testnet11", record.clone()).unwrap();
+        assert!(validated.expires.unwrap() <= Instant::now() + RECORD_TTL);
+
+        record.expires = Some(Instant::now() + 2 * RECORD_TTL);
+        let validated = validate_record("testnet11", record.clone()).unwrap();
+        assert!(validated.expires.unwrap() <= Instant::now() + RECORD_TTL);
+
+        let expires = Instant::now() + Duration::from_secs(60);
+        record.expires = Some(expires);
+        let validated = validate_record("testnet11", record).unwrap();
+        assert_eq!(validated.expires, Some(expires));
+    }
+
+    #[test]
+    fn rejects_records_of_other_offers() {
+        let (_, mut record) = record();
+        let other = Offer::decode(fixtures::CAT_FOR_XCH).unwrap();
+        record.key = record_key("testnet11", &other.id());
+        assert!(matches!(
+            validate_record("testnet11", record.clone()),
+            Err(SplashError::InvalidOfferRecord(_))
+        ));
+
+        // Keys name the network too
+        let (_, record) = self::record();
+        assert!(matches!(
+            validate_record("mainnet", record),
+            Err(SplashError::InvalidOfferRecord(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_records() {
+        let (_, mut record) = record();
+        record.value = vec![0; MAX_RECORD_SIZE + 1];
+        assert!(matches!(
+            validate_record("testnet11", record.clone()),
+            Err(SplashError::InvalidOfferRecord(_))
+        ));
+
+        record.value = b"not an offer".to_vec();
+        assert!(validate_record("testnet11", record).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Verification of the aggregated BLS signature of an offer's spend bundle, following the
+//! AGG_SIG rules of the Chia consensus (augmented scheme, network specific additional data).
+
+use crate::cancellation::Cancellation;
+use crate::conditions::{self, int_to_bytes};
+use crate::offer::Coin;
+use crate::{MessageAcceptance, MessageValidator, Offer, SplashError};
+use async_trait::async_trait;
+use chia_bls::{aggregate_verify, sign, verify, PublicKey, SecretKey, Signature};
+use libp2p::PeerId;
+use log::warn;
+use sha2::{Digest, Sha256};
+
+const MAX_AGG_SIG_MESSAGE_SIZE: usize = 1024;
+
+/// Rejects offers whose aggregated signature doesn't match their AGG_SIG conditions
+pub(crate) struct SignatureValidator {
+    genesis_challenge: [u8; 32],
+}
+
+impl SignatureValidator {
+    pub fn new(genesis_challenge: [u8; 32]) -> Self {
+        SignatureValidator { genesis_challenge }
+    }
+}
+
+#[async_trait]
+impl MessageValidator for SignatureValidator {
+    async fn validate(&self, offer: &Offer, propagation_source: &PeerId) -> MessageAcceptance {
+        let offer = offer.clone();
+        let genesis_challenge = self.genesis_challenge;
+
+        // Pairings are expensive, keep them away from the async workers
+        match tokio::task::spawn_blocking(move || verify_offer(&offer, &genesis_challenge)).await {
+            Ok(Ok(())) => MessageAcceptance::Accept,
+            Ok(Err(e)) => {
+                warn!(
+                    "Received invalid message from {}: {}",
+                    propagation_source, e
+                );
+                MessageAcceptance::Reject
+            }
+            Err(e) => {
+                warn!("Signature verification failed: {}", e);
+                MessageAcceptance::Ignore
+            }
+        }
+    }
+}
+
+/// Verifies the aggregated signature of the offer's spend bundle against the AGG_SIG conditions
+/// of its coin spends. Offers whose conditions couldn't be computed are not checked.
+pub(crate) fn verify_offer(offer: &Offer, genesis_challenge: &[u8; 32]) -> Result<(), SplashError> {
+    let Some(conditions) = offer.conditions() else {
+        return Ok(());
+    };
+
+    let invalid = || SplashError::InvalidSignature(offer.id());
+
+    let mut pairs = Vec::new();
+
+    for (coin_spend, spend_conditions) in offer.spend_bundle().coin_spends.iter().zip(conditions) {
+        for condition in spend_conditions {
+            if !(conditions::AGG_SIG_PARENT..=conditions::AGG_SIG_ME).contains(&condition.opcode) {
+                continue;
+            }
+
+            let [public_key, message, ..] = condition.args.as_slice() else {
+                return Err(invalid());
+            };
+
+            if message.len() > MAX_AGG_SIG_MESSAGE_SIZE {
+                return Err(invalid());
+            }
+
+            let public_key = public_key_arg(public_key).ok_or_else(invalid)?;
+
+            let message = signed_message(
+                condition.opcode,
+                message,
+                &coin_spend.coin,
+                genesis_challenge,
+            );
+
+            pairs.push((public_key, message));
+        }
+    }
+
+    // Without AGG_SIG conditions only the point at infinity verifies
+    let signature =
+        Signature::from_bytes(&offer.spend_bundle().aggregated_signature).map_err(|_| invalid())?;
+
+    let pairs = pairs
+        .iter()
+        .map(|(public_key, message)| (public_key, message.as_slice()));
+
+    if aggregate_verify(&signature, pairs) {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Signs a cancellation of `offer` with the secret key of one of the coins it spends
+pub(crate) fn sign_cancellation(
+    offer: &Offer,
+    secret_key: &[u8; 32],
+    genesis_challenge: &[u8; 32],
+) -> Result<Cancellation, SplashError> {
+    let secret_key = SecretKey::from_bytes(secret_key)
+        .map_err(|_| SplashError::InvalidCancellation("invalid secret key".to_string()))?;
+    let public_key = secret_key.public_key().to_bytes();
+
+    if !controls_coin(offer, &public_key) {
+        return Err(SplashError::InvalidCancellation(
+            "key doesn't control any coin of the offer".to_string(),
+        ));
+    }
+
+    let message = Cancellation::message(&offer.id(), genesis_challenge);
+
+    Ok(Cancellation {
+        offer: offer.compressed().clone(),
+        public_key,
+        signature: sign(&secret_key, message).to_bytes(),
+    })
+}
+
+/// Verifies a cancellation and returns the cancelled offer
+pub(crate) fn verify_cancellation(
+    cancellation: &Cancellation,
+    genesis_challenge: &[u8; 32],
+) -> Result<Offer, SplashError> {
+    let offer = Offer::from_compressed(cancellation.offer.clone())?;
+
+    let invalid = |reason: &str| SplashError::InvalidCancellation(reason.to_string());
+
+    if !controls_coin(&offer, &cancellation.public_key) {
+        return Err(invalid("key doesn't control any coin of the offer"));
+    }
+
+    let public_key =
+        public_key_arg(&cancellation.public_key).ok_or_else(|| invalid("invalid public key"))?;
+    let signature =
+        Signature::from_bytes(&cancellation.signature).map_err(|_| invalid("invalid signature"))?;
+
+    let message = Cancellation::message(&offer.id(), genesis_challenge);
+
+    if verify(&signature, &public_key, message) {
+        Ok(offer)
+    } else {
+        Err(invalid("signature doesn't match"))
+    }
+}
+
+/// Parses a public key, Chia doesn't allow the point at infinity in AGG_SIG conditions
+fn public_key_arg(bytes: &[u8]) -> Option<PublicKey> {
+    let public_key = PublicKey::from_bytes(bytes.try_into().ok()?).ok()?;
+    (!public_key.is_inf()).then_some(public_key)
+}
+
+/// Whether `public_key` has to sign for one of the coins the offer spends. Settlement coins are
+/// created by the offer itself, so they don't count.
+fn controls_coin(offer: &Offer, public_key: &[u8; 48]) -> bool {
+    let Some(conditions) = offer.conditions() else {
+        return false;
+    };
+
+    offer
+        .spend_bundle()
+        .coin_spends
+        .iter()
+        .zip(conditions)
+        .filter(|(coin_spend, _)| coin_spend.coin.parent_coin_info != [0; 32])
+        .flat_map(|(_, spend_conditions)| spend_conditions)
+        .any(|condition| {
+            (conditions::AGG_SIG_PARENT..=conditions::AGG_SIG_ME).contains(&condition.opcode)
+                && condition.args.first().map(Vec::as_slice) == Some(public_key.as_slice())
+        })
+}
+
+/// The message signed for an AGG_SIG condition with opcode `opcode`
+fn signed_message(
+    opcode: u16,
+    message: &[u8],
+    coin: &Coin,
+    genesis_challenge: &[u8; 32],
+) -> Vec<u8> {
+    let parent = coin.parent_coin_info.as_slice();
+    let puzzle_hash = coin.puzzle_hash.as_slice();
+    let amount = int_to_bytes(coin.amount);
+
+    let coin_data: Vec<&[u8]> = match opcode {
+        conditions::AGG_SIG_PARENT => vec![parent],
+        conditions::AGG_SIG_PUZZLE => vec![puzzle_hash],
+        conditions::AGG_SIG_AMOUNT => vec![&amount],
+        conditions::AGG_SIG_PUZZLE_AMOUNT => vec![puzzle_hash, &amount],
+        conditions::AGG_SIG_PARENT_AMOUNT => vec![parent, &amount],
+        conditions::AGG_SIG_PARENT_PUZZLE => vec![parent, puzzle_hash],
+        conditions::AGG_SIG_UNSAFE => return message.to_vec(),
+        _ => {
+            let coin_id = coin.coin_id();
+            return [message, &coin_id[..], genesis_challenge].concat();
+        }
+    };
+
+    // The newer AGG_SIG conditions each sign over their own additional data
+    let additional_data: [u8; 32] = Sha256::new()
+        .chain_update(genesis_challenge)
+        .chain_update([opcode as u8])
+        .finalize()
+        .into();
+
+    let mut signed = message.to_vec();
+    for data in coin_data {
+        signed.extend_from_slice(data);
+    }
+    signed.extend_from_slice(&additional_data);
+
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offer::decompress_offer;
+    use crate::{fixtures, TESTNET11_GENESIS_CHALLENGE};
+    use hex_literal::hex;
+
+    // Secret key of the maker of `fixtures::XCH_FOR_CAT`
+    const MAKER_KEY: [u8; 32] =
+        hex!("6dcb713e28c07d548672f4bc0aee46a5a4c81d6f31498db6ceb8110107aaacda");
+
+    /// `offer` with its aggregated signature replaced
+    fn with_signature(offer: &Offer, signature: [u8; 96]) -> Offer {
+        let mut bytes = decompress_offer(offer.compressed()).unwrap();
+        let len = bytes.len();
+        bytes[len - 96..].copy_from_slice(&signature);
+        Offer::from_compressed(fixtures::compress(6, &bytes)).unwrap()
+    }
+
+    #[test]
+    fn public_keys_match_the_chia_vectors() {
+        let public_key = |exponent: u8| {
+            let mut secret_key = [0; 32];
+            secret_key[31] = exponent;
+            SecretKey::from_bytes(&secret_key)
+                .unwrap()
+                .public_key()
+                .to_bytes()
+        };
+
+        assert_eq!(public_key(1), hex!("97f1d3a73197d7942695638c4fa9ac0fc3688c4f9774b905a14e3a3f171bac586c55e83ff97a1aeffb3af00adb22c6bb"));
+        assert_eq!(public_key(2), hex!("a572cbea904d67468808c8eb50a9450c9721db309128012543902d0ac358a62ae28f75bb8f1c7c42c39a8c5529bf0f4e"));
+        assert_eq!(public_key(5), hex!("b0e7791fb972fe014159aa33a98622da3cdc98ff707965e536d8636b5fcc5ac7a91a8c46e59a00dca575af0f18fb13dc"));
+    }
+
+    #[test]
+    fn verifies_offer_signatures() {
+        for encoded in [
+            fixtures::XCH_FOR_CAT,
+            fixtures::CAT_FOR_XCH,
+            fixtures::CAT_FOR_CAT,
+            fixtures::XCH_FOR_NFT,
+            fixtures::NFT_FOR_XCH,
+            fixtures::EXPIRING,
+        ] {
+            let offer = Offer::decode(encoded).unwrap();
+            verify_offer(&offer, &TESTNET11_GENESIS_CHALLENGE).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_offers_signed_for_another_network() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+
+        assert!(matches!(
+            verify_offer(&offer, &crate::MAINNET_GENESIS_CHALLENGE),
+            Err(SplashError::InvalidSignature(id)) if id == offer.id()
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_signatures() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let secret_key = SecretKey::from_bytes(&MAKER_KEY).unwrap();
+
+        let mut infinity = [0; 96];
+        infinity[0] = 0xc0;
+
+        for signature in [infinity, sign(&secret_key, b"not the offer").to_bytes()] {
+            let offer = with_signature(&offer, signature);
+            assert!(matches!(
+                verify_offer(&offer, &TESTNET11_GENESIS_CHALLENGE),
+                Err(SplashError::InvalidSignature(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Outbound TCP connections through a SOCKS5 proxy, such as a local Tor daemon
+//!
+//! Host names and `.onion` addresses are passed on to the proxy to resolve, so neither DNS queries
+//! nor connections leave the node directly. The transport can't listen, nodes using it are
+//! outbound-only.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use libp2p::core::transport::{DialOpts, ListenerId, TransportError, TransportEvent};
+use libp2p::core::Transport;
+use libp2p::multiaddr::Protocol;
+use libp2p::{tcp, Multiaddr};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Where to connect to, as understood by the proxy
+enum Target {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Socks5Transport {
+    proxy: SocketAddr,
+}
+
+impl Socks5Transport {
+    pub fn new(proxy: SocketAddr) -> Self {
+        Socks5Transport { proxy }
+    }
+}
+
+impl Transport for Socks5Transport {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = future::Pending<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(
+        &mut self,
+        _: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn remove_listener(&mut self, _: ListenerId) -> bool {
+        false
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        _: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some(target) = target(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+
+        let proxy = self.proxy;
+        Ok(async move { connect(proxy, target).await.map(tcp::tokio::TcpStream) }.boxed())
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Poll::Pending
+    }
+}
+
+/// The target of TCP and onion addresses, optionally followed by the peer ID
+fn target(addr: &Multiaddr) -> Option<Target> {
+    let mut protocols = addr.iter();
+
+    let target = match protocols.next()? {
+        Protocol::Ip4(ip) => Target::Ip((IpAddr::V4(ip), tcp_port(protocols.next())?).into()),
+        Protocol::Ip6(ip) => Target::Ip((IpAddr::V6(ip), tcp_port(protocols.next())?).into()),
+        Protocol::Dns(host) | Protocol::Dns4(host) | Protocol::Dns6(host) => {
+            Target::Domain(host.into_owned(), tcp_port(protocols.next())?)
+        }
+        Protocol::Onion3(onion) => {
+            let host = data_encoding::BASE32_NOPAD.encode(onion.hash());
+            Target::Domain(format!("{}.onion", host.to_lowercase()), onion.port())
+        }
+        _ => return None,
+    };
+
+    match (protocols.next(), protocols.next()) {
+        (None, _) | (Some(Protocol::P2p(_)), None) => Some(target),
+        _ => None,
+    }
+}
+
+fn tcp_port(protocol: Option<Protocol>) -> Option<u16> {
+    match protocol? {
+        Protocol::Tcp(port) => Some(port),
+        _ => None,
+    }
+}
+
+async fn connect(proxy: SocketAddr, target: Target) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.set_nodelay(true)?;
+
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [VERSION, NO_AUTHENTICATION] {
+        return Err(socks_error(
+            "proxy requires authentication or isn't a SOCKS5 proxy",
+        ));
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    let port = match target {
+        Target::Ip(SocketAddr::V4(addr)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Ip(SocketAddr::V6(addr)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Domain(host, port) => {
+            let length = u8::try_from(host.len()).map_err(|_| socks_error("host name too long"))?;
+            request.push(DOMAIN_NAME);
+            request.push(length);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(socks_error("invalid proxy reply"));
+    }
+    if reply[1] != 0 {
+        return Err(socks_error(match reply[1] {
+            2 => "connection not allowed by the proxy",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            _ => "proxy failed to connect",
+        }));
+    }
+
+    // Skip the address the proxy bound, followed by the port
+    let length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => stream.read_u8().await? as usize,
+        _ => return Err(socks_error("invalid proxy reply")),
+    };
+    let mut bound = vec![0; length + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+fn socks_error(message: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5: {}", message))
+}
@@ -0,0 +1,422 @@
+//! Dandelion++ stem phase, hiding which node published an offer
+//!
+//! Offers we publish aren't gossiped right away. They are passed along a random path of single
+//! peers (the stem) over a request-response protocol first, each node on the path either relays
+//! the offer to its successor or publishes it into gossipsub (fluffs it). Once per epoch, nodes
+//! pick two successors and whether they fluff every relayed offer. Offers relayed by the same
+//! peer, and our own offers, always go to the same one of the two, so paths don't change with
+//! every offer and an offer's path doesn't depend on where other offers came from. Every node on
+//! the stem starts an embargo timer and fluffs the offer itself if it wasn't gossiped before the
+//! timer ran out, offers dropped along the stem still get published.
+//!
+//! Requests contain a message envelope (`u32` length followed by the bytes), responses a single
+//! byte acknowledging it.
+
+use crate::validator::ValidationResult;
+use crate::{Asset, OfferId, MAX_MESSAGE_SIZE};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, PeerId, StreamProtocol};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::{Duration, SystemTime};
+use tokio::time::{self, Instant};
+
+const EPOCH: Duration = Duration::from_secs(10 * 60);
+// Peers relaying to us are routed to one of this many successors
+const SUCCESSORS: usize = 2;
+// Probability that a node fluffs all offers relayed to it during an epoch, stems are 10 hops long
+// on average
+const FLUFF_PROBABILITY: f64 = 0.1;
+
+// Long enough for an offer to travel the stem and the gossip mesh, randomized so the nodes on a
+// stem don't all fluff at once
+const EMBARGO: Duration = Duration::from_secs(30);
+const MAX_EMBARGO_JITTER: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct StemRequest {
+    pub envelope: Bytes,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StemCodec;
+
+#[async_trait]
+impl request_response::Codec for StemCodec {
+    type Protocol = StreamProtocol;
+    type Request = StemRequest;
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut length = [0; 4];
+        io.read_exact(&mut length).await?;
+
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stem message too large",
+            ));
+        }
+
+        let mut envelope = vec![0; length];
+        io.read_exact(&mut envelope).await?;
+
+        Ok(StemRequest {
+            envelope: Bytes::from(envelope),
+        })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut ack = [0; 1];
+        io.read_exact(&mut ack).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let mut data = Vec::with_capacity(4 + request.envelope.len());
+        data.extend_from_slice(&(request.envelope.len() as u32).to_be_bytes());
+        data.extend_from_slice(&request.envelope);
+
+        io.write_all(&data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        _: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&[0]).await
+    }
+}
+
+/// An offer in its stem phase
+pub(crate) struct StemOffer {
+    pub offer_id: OfferId,
+    pub compressed: Bytes,
+    /// `offer1...` string
+    pub offer: Bytes,
+    pub assets: Vec<Asset>,
+    pub expires_at: Option<SystemTime>,
+    /// Validation result of an offer a peer relayed to us, `None` for offers we publish
+    pub relayed: Option<ValidationResult>,
+}
+
+/// Routes offers along the stem and keeps them embargoed until they are fluffed
+pub(crate) struct Stem {
+    enabled: bool,
+    epoch_ends: Instant,
+    fluff: bool,
+    successors: Vec<PeerId>,
+    // Peers that couldn't take offers during this epoch
+    failed: HashSet<PeerId>,
+    // Successor of each peer relaying to us, `None` for our own offers
+    routes: HashMap<Option<PeerId>, PeerId>,
+    embargoes: HashMap<OfferId, (Instant, StemOffer)>,
+}
+
+impl Stem {
+    /// A disabled stem fluffs every offer right away
+    pub fn new(enabled: bool) -> Self {
+        Stem {
+            enabled,
+            epoch_ends: Instant::now(),
+            fluff: false,
+            successors: Vec::new(),
+            failed: HashSet::new(),
+            routes: HashMap::new(),
+            embargoes: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The peer to relay an offer from `inbound` to, `None` if it has to be fluffed. Offers we
+    /// publish have no inbound peer, they are always relayed when we have peers.
+    pub fn successor(&mut self, peers: &[PeerId], inbound: Option<PeerId>) -> Option<PeerId> {
+        if !self.enabled {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+        if now >= self.epoch_ends {
+            self.epoch_ends = now + EPOCH;
+            self.fluff = rng.gen_bool(FLUFF_PROBABILITY);
+            self.successors.clear();
+            self.failed.clear();
+            self.routes.clear();
+        }
+
+        if self.fluff && inbound.is_some() {
+            return None;
+        }
+
+        // Replace successors that disconnected
+        self.successors
+            .retain(|successor| peers.contains(successor));
+        let successors = &self.successors;
+        self.routes
+            .retain(|_, successor| successors.contains(successor));
+        let candidates: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer| !self.successors.contains(peer) && !self.failed.contains(peer))
+            .copied()
+            .collect();
+        self.successors.extend(
+            candidates.choose_multiple(&mut rng, SUCCESSORS.saturating_sub(self.successors.len())),
+        );
+
+        if let Some(successor) = self.routes.get(&inbound) {
+            return Some(*successor);
+        }
+
+        // Never relay an offer back to where it came from
+        let successors: Vec<PeerId> = self
+            .successors
+            .iter()
+            .filter(|successor| Some(**successor) != inbound)
+            .copied()
+            .collect();
+        let successor = *successors.choose(&mut rng)?;
+        self.routes.insert(inbound, successor);
+
+        Some(successor)
+    }
+
+    /// Stops relaying to a peer that couldn't take an offer until the epoch ends, another one is
+    /// picked for the next
+    pub fn remove_successor(&mut self, peer: &PeerId) {
+        self.failed.insert(*peer);
+        self.successors.retain(|successor| successor != peer);
+        self.routes.retain(|_, successor| successor != peer);
+    }
+
+    /// Holds an offer relayed to `successor` until it's gossiped, or fluffs it with the next
+    /// [`Stem::expired`] if it wasn't relayed
+    pub fn embargo(&mut self, offer: StemOffer, successor: Option<PeerId>) {
+        let deadline = match successor {
+            Some(_) => {
+                Instant::now()
+                    + EMBARGO
+                    + MAX_EMBARGO_JITTER.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+            }
+            None => Instant::now(),
+        };
+
+        self.embargoes.insert(offer.offer_id, (deadline, offer));
+    }
+
+    pub fn is_embargoed(&self, offer_id: &OfferId) -> bool {
+        self.embargoes.contains_key(offer_id)
+    }
+
+    /// Fluffs an embargoed offer with the next [`Stem::expired`], the stem was broken
+    pub fn fluff(&mut self, offer_id: &OfferId) {
+        if let Some((deadline, _)) = self.embargoes.get_mut(offer_id) {
+            *deadline = Instant::now();
+        }
+    }
+
+    /// Lifts the embargo of an offer that was gossiped
+    pub fn lift(&mut self, offer_id: &OfferId) -> Option<StemOffer> {
+        self.embargoes.remove(offer_id).map(|(_, offer)| offer)
+    }
+
+    /// Waits for the embargo of an offer to run out, it has to be fluffed
+    pub async fn expired(&mut self) -> StemOffer {
+        let Some((offer_id, deadline)) = self
+            .embargoes
+            .iter()
+            .map(|(offer_id, (deadline, _))| (*offer_id, *deadline))
+            .min_by_key(|(_, deadline)| *deadline)
+        else {
+            return futures::future::pending().await;
+        };
+
+        time::sleep_until(deadline).await;
+
+        self.lift(&offer_id).expect("embargo exists")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(id: u8) -> StemOffer {
+        StemOffer {
+            offer_id: OfferId([id; 32]),
+            compressed: Bytes::new(),
+            offer: Bytes::new(),
+            assets: Vec::new(),
+            expires_at: None,
+            relayed: None,
+        }
+    }
+
+    fn peers(count: usize) -> Vec<PeerId> {
+        (0..count).map(|_| PeerId::random()).collect()
+    }
+
+    fn relaying() -> Stem {
+        let mut stem = Stem::new(true);
+        stem.epoch_ends = Instant::now() + EPOCH;
+        stem
+    }
+
+    #[test]
+    fn routes_every_inbound_peer_to_one_successor() {
+        let peers = peers(10);
+        let mut stem = relaying();
+
+        let mut successors = HashSet::new();
+        for inbound in peers.iter().map(|peer| Some(*peer)).chain([None]) {
+            let successor = stem.successor(&peers, inbound).unwrap();
+            assert_ne!(Some(successor), inbound);
+            for _ in 0..10 {
+                assert_eq!(stem.successor(&peers, inbound), Some(successor));
+            }
+            successors.insert(successor);
+        }
+
+        assert!(successors.len() <= SUCCESSORS);
+    }
+
+    #[test]
+    fn never_relays_back_to_the_inbound_peer() {
+        let peers = peers(1);
+        let mut stem = relaying();
+
+        assert_eq!(stem.successor(&peers, Some(peers[0])), None);
+        assert_eq!(stem.successor(&peers, None), Some(peers[0]));
+    }
+
+    #[test]
+    fn replaces_successors_that_are_gone() {
+        let peers = peers(3);
+        let mut stem = relaying();
+
+        let successor = stem.successor(&peers, None).unwrap();
+        stem.remove_successor(&successor);
+        let replacement = stem.successor(&peers, None).unwrap();
+        assert_ne!(replacement, successor);
+
+        let connected: Vec<PeerId> = peers
+            .iter()
+            .filter(|peer| **peer != replacement)
+            .copied()
+            .collect();
+        let successor = stem.successor(&connected, None).unwrap();
+        assert!(connected.contains(&successor));
+    }
+
+    #[test]
+    fn picks_new_routes_every_epoch() {
+        let peers = peers(10);
+        let mut stem = relaying();
+
+        let mut successors = HashSet::new();
+        for _ in 0..20 {
+            stem.epoch_ends = Instant::now();
+            successors.insert(stem.successor(&peers, None).unwrap());
+        }
+
+        assert!(successors.len() > 1);
+    }
+
+    #[test]
+    fn fluffs_relayed_offers_in_fluff_epochs() {
+        let peers = peers(3);
+        let mut stem = relaying();
+        stem.fluff = true;
+
+        assert_eq!(stem.successor(&peers, Some(peers[0])), None);
+        // Our own offers always take the stem
+        assert!(stem.successor(&peers, None).is_some());
+    }
+
+    #[test]
+    fn disabled_stems_fluff_everything() {
+        let peers = peers(3);
+        let mut stem = Stem::new(false);
+
+        assert_eq!(stem.successor(&peers, None), None);
+        assert_eq!(stem.successor(&peers, Some(peers[0])), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fluffs_offers_without_successor_right_away() {
+        let mut stem = relaying();
+        stem.embargo(offer(1), None);
+
+        let fluffed = time::timeout(Duration::ZERO, stem.expired()).await;
+        assert_eq!(fluffed.unwrap().offer_id, OfferId([1; 32]));
+        assert!(!stem.is_embargoed(&OfferId([1; 32])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fluffs_offers_once_their_embargo_runs_out() {
+        let mut stem = relaying();
+        stem.embargo(offer(1), Some(PeerId::random()));
+
+        assert!(time::timeout(EMBARGO, stem.expired()).await.is_err());
+        assert!(stem.is_embargoed(&OfferId([1; 32])));
+
+        let fluffed = time::timeout(MAX_EMBARGO_JITTER, stem.expired()).await;
+        assert_eq!(fluffed.unwrap().offer_id, OfferId([1; 32]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fluffs_offers_when_the_stem_breaks() {
+        let mut stem = relaying();
+        stem.embargo(offer(1), Some(PeerId::random()));
+        stem.embargo(offer(2), Some(PeerId::random()));
+
+        stem.fluff(&OfferId([2; 32]));
+        let fluffed = time::timeout(Duration::ZERO, stem.expired()).await;
+        assert_eq!(fluffed.unwrap().offer_id, OfferId([2; 32]));
+        assert!(stem.is_embargoed(&OfferId([1; 32])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_gossiped_offers_from_being_fluffed() {
+        let mut stem = relaying();
+        stem.embargo(offer(1), Some(PeerId::random()));
+
+        assert_eq!(
+            stem.lift(&OfferId([1; 32])).map(|offer| offer.offer_id),
+            Some(OfferId([1; 32]))
+        );
+        assert!(time::timeout(EMBARGO + MAX_EMBARGO_JITTER, stem.expired())
+            .await
+            .is_err());
+    }
+}
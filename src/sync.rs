@@ -0,0 +1,259 @@
+//! Periodic sync of recent offers between connected peers, recovering offers gossip lost
+//!
+//! A node sends one of its peers a bloom filter of the IDs of its recent offers, the peer replies
+//! with the offers it holds that aren't in the filter. Every request uses a new seed, so offers
+//! missed due to false positives are found in later rounds.
+//!
+//! Requests contain the seed (`u32`), the number of hash functions (`u8`) and the filter bits
+//! (`u32` length followed by the bytes). Responses are the same as catch-up responses.
+
+use crate::catchup::{self, CatchupResponse};
+use crate::OfferId;
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+use sha2::{Digest, Sha256};
+use std::io;
+
+// About 1% false positives
+const BITS_PER_OFFER: usize = 10;
+const HASHES: u8 = 7;
+
+// Enough for 50 000 offers, far more than a node keeps
+const MAX_FILTER_SIZE: usize = 64 * 1024;
+const MAX_HASHES: u8 = 16;
+
+#[derive(Debug)]
+pub(crate) struct SyncRequest {
+    pub known: BloomFilter,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BloomFilter {
+    seed: u32,
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `offers` offers
+    pub fn new(offers: usize, seed: u32) -> Self {
+        let size = (offers * BITS_PER_OFFER)
+            .div_ceil(8)
+            .clamp(1, MAX_FILTER_SIZE);
+
+        BloomFilter {
+            seed,
+            hashes: HASHES,
+            bits: vec![0; size],
+        }
+    }
+
+    pub fn insert(&mut self, offer_id: &OfferId) {
+        for index in self.indices(offer_id) {
+            self.bits[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, offer_id: &OfferId) -> bool {
+        !self.bits.is_empty()
+            && self
+                .indices(offer_id)
+                .all(|index| self.bits[index / 8] & (1 << (index % 8)) != 0)
+    }
+
+    // Double hashing, the filter is far too small for the bias to matter
+    fn indices(&self, offer_id: &OfferId) -> impl Iterator<Item = usize> {
+        let hash = Sha256::new()
+            .chain_update(self.seed.to_be_bytes())
+            .chain_update(offer_id.0)
+            .finalize();
+        let first = u64::from_be_bytes(hash[..8].try_into().expect("hash is 32 bytes"));
+        let second = u64::from_be_bytes(hash[8..16].try_into().expect("hash is 32 bytes"));
+        let bits = (self.bits.len() * 8).max(1) as u64;
+
+        (0..self.hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits) as usize)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SyncCodec;
+
+#[async_trait]
+impl request_response::Codec for SyncCodec {
+    type Protocol = StreamProtocol;
+    type Request = SyncRequest;
+    type Response = CatchupResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut header = [0; 9];
+        io.read_exact(&mut header).await?;
+
+        let seed = u32::from_be_bytes(header[..4].try_into().expect("header is 9 bytes"));
+        let hashes = header[4];
+        let size = u32::from_be_bytes(header[5..].try_into().expect("header is 9 bytes")) as usize;
+
+        if hashes > MAX_HASHES || size > MAX_FILTER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sync filter too large",
+            ));
+        }
+
+        let mut bits = vec![0; size];
+        io.read_exact(&mut bits).await?;
+
+        Ok(SyncRequest {
+            known: BloomFilter { seed, hashes, bits },
+        })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        catchup::read_response(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let BloomFilter { seed, hashes, bits } = request.known;
+
+        let mut data = Vec::with_capacity(9 + bits.len());
+        data.extend_from_slice(&seed.to_be_bytes());
+        data.push(hashes);
+        data.extend_from_slice(&(bits.len() as u32).to_be_bytes());
+        data.extend_from_slice(&bits);
+
+        io.write_all(&data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        catchup::write_response(io, response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    const PROTOCOL: StreamProtocol = StreamProtocol::new("/splash/sync/1");
+
+    fn offer_id(id: u32) -> OfferId {
+        OfferId(Sha256::digest(id.to_be_bytes()).into())
+    }
+
+    #[test]
+    fn contains_inserted_offers() {
+        let mut filter = BloomFilter::new(1000, 1);
+        for id in 0..1000 {
+            filter.insert(&offer_id(id));
+        }
+
+        assert!((0..1000).all(|id| filter.contains(&offer_id(id))));
+        let false_positives = (1000..11_000)
+            .filter(|id| filter.contains(&offer_id(*id)))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn seeds_change_false_positives() {
+        let filters: Vec<BloomFilter> = [1, 2]
+            .into_iter()
+            .map(|seed| {
+                let mut filter = BloomFilter::new(100, seed);
+                for id in 0..100 {
+                    filter.insert(&offer_id(id));
+                }
+                filter
+            })
+            .collect();
+
+        let false_positives = |filter: &BloomFilter| -> Vec<u32> {
+            (100..10_000)
+                .filter(|id| filter.contains(&offer_id(*id)))
+                .collect()
+        };
+        assert_ne!(false_positives(&filters[0]), false_positives(&filters[1]));
+    }
+
+    #[test]
+    fn sizes_filters_for_their_offers() {
+        assert_eq!(BloomFilter::new(0, 1).bits.len(), 1);
+        assert_eq!(BloomFilter::new(100, 1).bits.len(), 125);
+        assert_eq!(BloomFilter::new(1_000_000, 1).bits.len(), MAX_FILTER_SIZE);
+
+        // Peers may send empty filters
+        let empty = BloomFilter {
+            seed: 1,
+            hashes: HASHES,
+            bits: Vec::new(),
+        };
+        assert!(!empty.contains(&offer_id(0)));
+    }
+
+    #[tokio::test]
+    async fn round_trips_requests() {
+        let mut known = BloomFilter::new(10, 42);
+        known.insert(&offer_id(0));
+
+        let mut io = Cursor::new(Vec::new());
+        SyncCodec
+            .write_request(
+                &PROTOCOL,
+                &mut io,
+                SyncRequest {
+                    known: known.clone(),
+                },
+            )
+            .await
+            .unwrap();
+
+        io.set_position(0);
+        let request = SyncCodec.read_request(&PROTOCOL, &mut io).await.unwrap();
+        assert_eq!(request.known.seed, 42);
+        assert_eq!(request.known.hashes, HASHES);
+        assert_eq!(request.known.bits, known.bits);
+        assert!(request.known.contains(&offer_id(0)));
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_requests() {
+        for (hashes, size) in [(MAX_HASHES + 1, 1), (HASHES, MAX_FILTER_SIZE as u32 + 1)] {
+            let mut data = vec![0, 0, 0, 1, hashes];
+            data.extend_from_slice(&size.to_be_bytes());
+            data.resize(data.len() + size as usize, 0);
+
+            let result = SyncCodec
+                .read_request(&PROTOCOL, &mut Cursor::new(data))
+                .await;
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
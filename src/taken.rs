@@ -0,0 +1,424 @@
+//! Notices that an offer was taken, detected by watching the coins of received offers
+//!
+//! A notice carries the compressed offer, the ID of one of its coins and the height that coin was
+//! spent at. Peers check that the coin belongs to the offer and that it is actually spent
+//! according to their [`CoinStateSource`], notices that can't be checked are dropped. Spending a
+//! coin of an offer in any other way invalidates the offer too, so offers cancelled on chain are
+//! reported as taken.
+//!
+//! Payloads use the Chia streamable format: the offer (`u32` length followed by the bytes), the
+//! coin ID (32 bytes) and the height (`u32`).
+
+use crate::full_node::CoinStateSource;
+use crate::offer::Reader;
+use crate::{Asset, Offer, OfferId, SplashError};
+use bytes::Bytes;
+use log::{debug, warn};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Keeps RPC requests small, the full node limits their size
+const MAX_COINS_PER_REQUEST: usize = 500;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TakenNotice {
+    /// Compressed bytes of the taken offer
+    pub offer: Bytes,
+    /// The offer's coin that was spent
+    pub coin_id: [u8; 32],
+    pub spent_height: u32,
+}
+
+impl TakenNotice {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.offer.len() + 32 + 4);
+        bytes.extend_from_slice(&(self.offer.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.offer);
+        bytes.extend_from_slice(&self.coin_id);
+        bytes.extend_from_slice(&self.spent_height.to_be_bytes());
+        bytes
+    }
+
+    /// Parses a notice, the offer shares the memory of `bytes`
+    pub fn from_bytes(bytes: Bytes) -> Result<TakenNotice, SplashError> {
+        let mut reader = Reader::new(&bytes);
+
+        let mut read = || -> Result<_, String> {
+            let offer_len = reader.bytes()?.len();
+            let coin_id = reader.array()?;
+            let spent_height = reader.u32()?;
+            Ok((offer_len, coin_id, spent_height))
+        };
+        let (offer_len, coin_id, spent_height) = read().map_err(SplashError::InvalidTakenNotice)?;
+
+        if !reader.is_empty() {
+            return Err(SplashError::InvalidTakenNotice(format!(
+                "{} trailing bytes",
+                reader.remaining()
+            )));
+        }
+
+        Ok(TakenNotice {
+            offer: bytes.slice(4..4 + offer_len),
+            coin_id,
+            spent_height,
+        })
+    }
+
+    /// Checks that the spent coin belongs to the offer and returns the offer
+    pub(crate) fn verify(&self) -> Result<Offer, SplashError> {
+        let offer = Offer::from_compressed(self.offer.clone())?;
+
+        if !offer.coin_ids().contains(&self.coin_id) {
+            return Err(SplashError::InvalidTakenNotice(
+                "coin doesn't belong to the offer".to_string(),
+            ));
+        }
+
+        Ok(offer)
+    }
+}
+
+pub(crate) enum Watch {
+    /// Starts watching the coins of a received offer
+    Offer {
+        offer_id: OfferId,
+        compressed: Bytes,
+        coin_ids: Vec<[u8; 32]>,
+        assets: Vec<Asset>,
+        expires_at: Option<SystemTime>,
+    },
+    /// Stops watching an offer another peer reported as taken
+    Taken(OfferId),
+}
+
+/// A watched offer whose coin got spent
+pub(crate) struct Observed {
+    pub offer_id: OfferId,
+    pub assets: Vec<Asset>,
+    pub notice: TakenNotice,
+}
+
+struct WatchedOffer {
+    compressed: Bytes,
+    coin_ids: Vec<[u8; 32]>,
+    assets: Vec<Asset>,
+    expires_at: Option<SystemTime>,
+}
+
+/// Spawns a task polling `source` for spends of the coins of watched offers. At most `capacity`
+/// offers are watched, the least recently received are dropped first.
+pub(crate) fn spawn_watcher(
+    source: Arc<dyn CoinStateSource>,
+    capacity: usize,
+) -> (mpsc::Sender<Watch>, mpsc::Receiver<Observed>) {
+    let (watch_tx, mut watch_rx) = mpsc::channel(256);
+    let (notice_tx, notice_rx) = mpsc::channel(256);
+
+    let mut watched: LruCache<OfferId, WatchedOffer> =
+        LruCache::new(NonZeroUsize::new(capacity.max(1)).expect("capacity is not zero"));
+
+    tokio::spawn(async move {
+        let mut poll_interval = time::interval(POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                watch = watch_rx.recv() => match watch {
+                    Some(Watch::Offer { offer_id, compressed, coin_ids, assets, expires_at }) => {
+                        if !coin_ids.is_empty() {
+                            watched.put(offer_id, WatchedOffer { compressed, coin_ids, assets, expires_at });
+                        }
+                    },
+                    Some(Watch::Taken(offer_id)) => {
+                        watched.pop(&offer_id);
+                    },
+                    None => break,
+                },
+                _ = poll_interval.tick() => {
+                    for observed in poll(source.as_ref(), &mut watched).await {
+                        if notice_tx.send(observed).await.is_err() {
+                            return;
+                        }
+                    }
+                },
+            }
+        }
+    });
+
+    (watch_tx, notice_rx)
+}
+
+/// Drops expired offers and returns a notice for every watched offer with a spent coin
+async fn poll(
+    source: &dyn CoinStateSource,
+    watched: &mut LruCache<OfferId, WatchedOffer>,
+) -> Vec<Observed> {
+    let now = SystemTime::now();
+    let expired: Vec<OfferId> = watched
+        .iter()
+        .filter(|(_, offer)| offer.expires_at.is_some_and(|expires_at| now >= expires_at))
+        .map(|(offer_id, _)| *offer_id)
+        .collect();
+    for offer_id in expired {
+        watched.pop(&offer_id);
+    }
+
+    let coin_ids: Vec<[u8; 32]> = watched
+        .iter()
+        .flat_map(|(_, offer)| offer.coin_ids.iter().copied())
+        .collect();
+
+    let mut spent = Vec::new();
+    for chunk in coin_ids.chunks(MAX_COINS_PER_REQUEST) {
+        match source.coin_records(chunk).await {
+            Ok(records) => spent.extend(records.into_iter().filter(|record| record.is_spent())),
+            Err(e) => {
+                warn!("Failed to watch offer coins: {}", e);
+                return Vec::new();
+            }
+        }
+    }
+
+    let mut observed = Vec::new();
+    for record in spent {
+        let coin_id = record.coin.coin_id();
+        let Some(offer_id) = watched
+            .iter()
+            .find(|(_, offer)| offer.coin_ids.contains(&coin_id))
+            .map(|(offer_id, _)| *offer_id)
+        else {
+            continue;
+        };
+
+        if let Some(offer) = watched.pop(&offer_id) {
+            debug!(
+                "Offer {} taken, coin {} spent at height {}",
+                offer_id,
+                hex::encode(coin_id),
+                record.spent_block_index
+            );
+            let notice = TakenNotice {
+                offer: offer.compressed,
+                coin_id,
+                spent_height: record.spent_block_index,
+            };
+            observed.push(Observed {
+                offer_id,
+                assets: offer.assets,
+                notice,
+            });
+        }
+    }
+
+    observed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::full_node::CoinRecord;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// Coin states kept in memory, fails while `records` is `None`
+    #[derive(Default)]
+    struct Coins {
+        records: Mutex<Option<Vec<CoinRecord>>>,
+    }
+
+    impl Coins {
+        fn new(records: Vec<CoinRecord>) -> Self {
+            Coins {
+                records: Mutex::new(Some(records)),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl CoinStateSource for Coins {
+        async fn coin_records(
+            &self,
+            coin_ids: &[[u8; 32]],
+        ) -> Result<Vec<CoinRecord>, SplashError> {
+            let records = self.records.lock().unwrap();
+            let records = records
+                .as_ref()
+                .ok_or_else(|| SplashError::FullNodeRpc("unavailable".to_string()))?;
+            Ok(records
+                .iter()
+                .filter(|record| coin_ids.contains(&record.coin.coin_id()))
+                .cloned()
+                .collect())
+        }
+    }
+
+    /// The record of the first coin `offer` spends
+    fn coin_record(offer: &Offer, spent_block_index: u32) -> CoinRecord {
+        CoinRecord {
+            coin: offer.spend_bundle().coin_spends[0].coin.clone(),
+            confirmed_block_index: 100,
+            spent_block_index,
+            coinbase: false,
+            timestamp: 1_700_000_000,
+        }
+    }
+
+    fn notice(offer: &Offer) -> TakenNotice {
+        TakenNotice {
+            offer: offer.compressed().clone(),
+            coin_id: offer.coin_ids()[0],
+            spent_height: 120,
+        }
+    }
+
+    fn watched(offers: &[(&Offer, Option<SystemTime>)]) -> LruCache<OfferId, WatchedOffer> {
+        let mut watched = LruCache::new(NonZeroUsize::new(10).unwrap());
+        for (offer, expires_at) in offers {
+            watched.put(
+                offer.id(),
+                WatchedOffer {
+                    compressed: offer.compressed().clone(),
+                    coin_ids: offer.coin_ids(),
+                    assets: offer.assets(),
+                    expires_at: *expires_at,
+                },
+            );
+        }
+        watched
+    }
+
+    #[test]
+    fn encodes_streamable_notices() {
+        let notice = TakenNotice {
+            offer: Bytes::from_static(b"offer"),
+            coin_id: [7; 32],
+            spent_height: 0x0102_0304,
+        };
+        let bytes = notice.to_bytes();
+
+        let mut expected = vec![0, 0, 0, 5];
+        expected.extend_from_slice(b"offer");
+        expected.extend_from_slice(&[7; 32]);
+        expected.extend_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(bytes, expected);
+
+        assert_eq!(TakenNotice::from_bytes(bytes.into()).unwrap(), notice);
+    }
+
+    #[test]
+    fn rejects_invalid_notices() {
+        let bytes = notice(&Offer::decode(fixtures::XCH_FOR_CAT).unwrap()).to_bytes();
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        for invalid in [&bytes[..bytes.len() - 1], &trailing, &[0, 0, 0, 9, 1]] {
+            assert!(matches!(
+                TakenNotice::from_bytes(Bytes::copy_from_slice(invalid)),
+                Err(SplashError::InvalidTakenNotice(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn verifies_the_coin_belongs_to_the_offer() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let mut notice = notice(&offer);
+        assert_eq!(notice.verify().unwrap().id(), offer.id());
+
+        notice.coin_id = [0; 32];
+        assert!(matches!(
+            notice.verify(),
+            Err(SplashError::InvalidTakenNotice(_))
+        ));
+
+        notice.offer = Bytes::from_static(b"not an offer");
+        assert!(notice.verify().is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_offers_with_spent_coins() {
+        let taken = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let open = Offer::decode(fixtures::CAT_FOR_XCH).unwrap();
+        let source = Coins::new(vec![coin_record(&taken, 120), coin_record(&open, 0)]);
+        let mut watched = watched(&[(&taken, None), (&open, None)]);
+
+        let observed = poll(&source, &mut watched).await;
+
+        assert_eq!(observed.len(), 1);
+        assert_eq!(observed[0].offer_id, taken.id());
+        assert_eq!(observed[0].assets, taken.assets());
+        assert_eq!(observed[0].notice, notice(&taken));
+        // Taken offers aren't watched any more, unspent ones are
+        assert!(!watched.contains(&taken.id()));
+        assert!(watched.contains(&open.id()));
+        assert!(poll(&source, &mut watched).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stops_watching_expired_offers() {
+        let expired = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let open = Offer::decode(fixtures::CAT_FOR_XCH).unwrap();
+        let source = Coins::new(vec![coin_record(&expired, 120), coin_record(&open, 0)]);
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let mut watched = watched(&[
+            (&expired, Some(SystemTime::now())),
+            (&open, Some(expires_at)),
+        ]);
+
+        assert!(poll(&source, &mut watched).await.is_empty());
+        assert!(!watched.contains(&expired.id()));
+        assert!(watched.contains(&open.id()));
+    }
+
+    #[tokio::test]
+    async fn keeps_watching_while_the_source_fails() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let source = Coins::default();
+        let mut watched = watched(&[(&offer, None)]);
+
+        assert!(poll(&source, &mut watched).await.is_empty());
+        assert!(watched.contains(&offer.id()));
+
+        *source.records.lock().unwrap() = Some(vec![coin_record(&offer, 120)]);
+        assert_eq!(poll(&source, &mut watched).await.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watches_received_offers() {
+        let taken = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let reported = Offer::decode(fixtures::CAT_FOR_XCH).unwrap();
+        let source = Arc::new(Coins::new(Vec::new()));
+        let (watch, mut observed) = spawn_watcher(source.clone(), 10);
+
+        for offer in [&taken, &reported] {
+            watch
+                .send(Watch::Offer {
+                    offer_id: offer.id(),
+                    compressed: offer.compressed().clone(),
+                    coin_ids: offer.coin_ids(),
+                    assets: offer.assets(),
+                    expires_at: None,
+                })
+                .await
+                .unwrap();
+        }
+        // Another peer reported this one, so its spent coin isn't reported again
+        watch.send(Watch::Taken(reported.id())).await.unwrap();
+        *source.records.lock().unwrap() =
+            Some(vec![coin_record(&taken, 120), coin_record(&reported, 120)]);
+
+        let observed = time::timeout(2 * POLL_INTERVAL, observed.recv())
+            .await
+            .expect("offer was reported as taken")
+            .unwrap();
+        assert_eq!(observed.offer_id, taken.id());
+        assert_eq!(observed.notice, notice(&taken));
+    }
+}
@@ -1,8 +1,10 @@
 use libp2p::identity;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use splash::ReceivedOffer;
 use std::fs::{self, File};
 use std::io;
+use std::time::UNIX_EPOCH;
 
 pub fn load_keypair_from_file(file_path: &str) -> io::Result<identity::Keypair> {
     let contents = fs::read_to_string(file_path)?;
@@ -20,15 +22,48 @@ pub fn save_keypair_to_file(keypair: &identity::Keypair, file_path: &str) -> io:
     Ok(())
 }
 
-pub async fn message_post_hook(endpoint: &str, offer: &str) -> Result<(), reqwest::Error> {
+pub async fn message_post_hook(
+    endpoint: &str,
+    offer_json: &serde_json::Value,
+) -> Result<(), reqwest::Error> {
     let client = reqwest::Client::new();
-
-    let message_json = json!({ "offer": offer });
-    client.post(endpoint).json(&message_json).send().await?;
+    client.post(endpoint).json(offer_json).send().await?;
 
     Ok(())
 }
 
+pub fn offer_json(received: &ReceivedOffer) -> serde_json::Value {
+    let expires_at = received
+        .expires_at
+        .and_then(|expires_at| expires_at.duration_since(UNIX_EPOCH).ok())
+        .map(|expires_at| expires_at.as_secs());
+
+    json!({
+        "offer": received.to_bech32(),
+        "offer_id": received.offer_id.to_string(),
+        "expires_at": expires_at,
+        "expires_at_height": received.expires_at_height,
+    })
+}
+
+/// Offer JSON of the offer cache API, including when and from whom the offer was first received
+pub fn cached_offer_json(received: &ReceivedOffer) -> serde_json::Value {
+    let mut offer_json = offer_json(received);
+    offer_json["first_seen"] = json!(received
+        .received_at
+        .duration_since(UNIX_EPOCH)
+        .map(|first_seen| first_seen.as_secs())
+        .unwrap_or_default());
+    offer_json["source"] = json!(received.propagation_source.to_string());
+    offer_json
+}
+
+#[derive(Deserialize)]
+pub struct OffersQuery {
+    /// Unix time in seconds
+    pub since: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct IdentityJson {
     identity: Vec<u8>,
@@ -0,0 +1,922 @@
+use crate::envelope::{self, Envelope, MessageType};
+use crate::full_node::CoinStateSource;
+#[cfg(feature = "bls")]
+use crate::{signature, Cancellation};
+use crate::{
+    Asset, Offer, OfferId, ReceivedCancellation, ReceivedMessage, ReceivedOffer, Splash,
+    SplashError, TakenNotice, TakenOffer,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use libp2p::gossipsub::{MessageAcceptance, MessageId, TopicHash};
+use libp2p::PeerId;
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex};
+
+/// Custom validation policy for received offers
+///
+/// Validators run after the built-in validation (size, encoding, spend bundle), in the order
+/// they were registered with [`crate::Splash::with_validator`]. The first result other than
+/// `Accept` decides the outcome reported to gossipsub: `Reject` penalizes the propagating peer,
+/// `Ignore` drops the message without penalty.
+#[async_trait]
+pub trait MessageValidator: Send + Sync {
+    async fn validate(&self, offer: &Offer, propagation_source: &PeerId) -> MessageAcceptance;
+
+    /// Validates a message received on an application topic, see [`crate::Splash::subscribe`].
+    /// Accepts every message by default.
+    async fn validate_application_message(
+        &self,
+        _topic: &TopicHash,
+        _data: &[u8],
+        _propagation_source: &PeerId,
+    ) -> MessageAcceptance {
+        MessageAcceptance::Accept
+    }
+}
+
+/// What the messages on a topic contain
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TopicKind {
+    /// Every offer
+    Offers,
+    /// Offers involving an asset
+    Asset(Asset),
+    /// Messages of an application, passed on as is
+    Application,
+}
+
+/// How a message reached us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Origin {
+    /// Through gossipsub, the validation result has to be reported back
+    Gossip,
+    /// From a peer we caught up or synced with
+    Peer,
+    /// Along a stem, it has to be relayed once it's valid
+    Stem,
+}
+
+pub(crate) struct ValidationJob {
+    pub message_id: MessageId,
+    pub propagation_source: PeerId,
+    pub topic: TopicHash,
+    pub topic_kind: TopicKind,
+    pub origin: Origin,
+    pub data: Bytes,
+    pub received_at: SystemTime,
+}
+
+pub(crate) struct ValidationResult {
+    pub message_id: MessageId,
+    pub propagation_source: PeerId,
+    pub origin: Origin,
+    pub acceptance: MessageAcceptance,
+    pub received: Option<Received>,
+}
+
+pub(crate) enum Received {
+    /// A valid offer and the IDs of the coins it spends
+    Offer(ReceivedOffer, Vec<[u8; 32]>),
+    Cancellation(ReceivedCancellation),
+    Taken(TakenOffer),
+    Message(ReceivedMessage),
+}
+
+/// Spawns `workers` tasks validating messages off the swarm loop. The job queue holds at most
+/// `queue_size` messages, the swarm loop is expected to `try_send` and ignore messages when it's
+/// full, batches from peers are queued from their own task.
+pub(crate) fn spawn_validation_workers(
+    workers: usize,
+    queue_size: usize,
+    validators: Vec<Arc<dyn MessageValidator>>,
+    genesis_challenge: [u8; 32],
+    coin_state: Option<Arc<dyn CoinStateSource>>,
+) -> (
+    mpsc::Sender<ValidationJob>,
+    mpsc::Receiver<ValidationResult>,
+) {
+    let (job_tx, job_rx) = mpsc::channel::<ValidationJob>(queue_size);
+    let (result_tx, result_rx) = mpsc::channel(queue_size);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+
+    for _ in 0..workers.max(1) {
+        let job_rx = job_rx.clone();
+        let result_tx = result_tx.clone();
+        let validators = validators.clone();
+        let coin_state = coin_state.clone();
+
+        tokio::spawn(async move {
+            loop {
+                // Only hold the lock while waiting for the next job
+                let Some(job) = job_rx.lock().await.recv().await else {
+                    break;
+                };
+
+                let result =
+                    validate(job, &validators, genesis_challenge, coin_state.as_deref()).await;
+
+                if result_tx.send(result).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    (job_tx, result_rx)
+}
+
+/// Queues a received message without waiting, messages arriving while the queue is full are
+/// ignored. Returns the result to report for an ignored message.
+pub(crate) fn enqueue(
+    queue: &mpsc::Sender<ValidationJob>,
+    job: ValidationJob,
+) -> Option<ValidationResult> {
+    let (TrySendError::Full(job) | TrySendError::Closed(job)) = queue.try_send(job).err()?;
+    warn!("Validation queue full, ignoring message {}", job.message_id);
+
+    Some(ValidationResult {
+        message_id: job.message_id,
+        propagation_source: job.propagation_source,
+        origin: job.origin,
+        acceptance: MessageAcceptance::Ignore,
+        received: None,
+    })
+}
+
+async fn validate(
+    job: ValidationJob,
+    validators: &[Arc<dyn MessageValidator>],
+    genesis_challenge: [u8; 32],
+    coin_state: Option<&dyn CoinStateSource>,
+) -> ValidationResult {
+    let ValidationJob {
+        message_id,
+        propagation_source,
+        topic,
+        topic_kind,
+        origin,
+        data,
+        received_at,
+    } = job;
+
+    if let TopicKind::Application = topic_kind {
+        let mut acceptance = MessageAcceptance::Accept;
+        for validator in validators {
+            acceptance = validator
+                .validate_application_message(&topic, &data, &propagation_source)
+                .await;
+            if !matches!(acceptance, MessageAcceptance::Accept) {
+                break;
+            }
+        }
+
+        let received = matches!(acceptance, MessageAcceptance::Accept).then(|| {
+            Received::Message(ReceivedMessage {
+                topic,
+                data,
+                message_id: message_id.clone(),
+                propagation_source,
+                received_at,
+            })
+        });
+
+        return ValidationResult {
+            message_id,
+            propagation_source,
+            origin,
+            acceptance,
+            received,
+        };
+    }
+
+    // Decompressing and parsing is CPU bound, keep it away from the async workers
+    let decoded = tokio::task::spawn_blocking(move || {
+        if envelope::is_legacy(&data) {
+            return Splash::validate_message(as_str(&data)?)
+                .map(|offer| Decoded::offer(offer, None, None));
+        }
+
+        let envelope = Envelope::from_bytes(data)?;
+        let (message_type, published_at) = (envelope.message_type, envelope.timestamp);
+
+        match message_type {
+            MessageType::Offer => Splash::validate_message(as_str(&envelope.payload)?)
+                .map(|offer| Decoded::offer(offer, Some(message_type), published_at)),
+            MessageType::BinaryOffer => Splash::validate_binary_message(envelope.payload)
+                .map(|offer| Decoded::offer(offer, Some(message_type), published_at)),
+            MessageType::OfferCancellation => {
+                verify_cancellation(envelope.payload, &genesis_challenge).map(
+                    |(offer_id, public_key)| Decoded::Cancellation {
+                        offer_id,
+                        public_key,
+                        published_at,
+                    },
+                )
+            }
+            MessageType::OfferTaken => {
+                let notice = TakenNotice::from_bytes(envelope.payload)?;
+                let offer = notice.verify()?;
+                Ok(Decoded::Taken {
+                    offer_id: offer.id(),
+                    coin_id: notice.coin_id,
+                    spent_height: notice.spent_height,
+                    published_at,
+                })
+            }
+        }
+    })
+    .await;
+
+    let decoded = match decoded {
+        Ok(Ok(decoded)) => decoded,
+        // Expired offers may still be in flight from honest peers and newer peers may send
+        // messages we don't understand yet, drop them without penalty
+        Ok(Err(
+            e @ (SplashError::OfferExpired(_)
+            | SplashError::UnsupportedEnvelopeVersion(_)
+            | SplashError::UnsupportedMessageType(_)),
+        )) => {
+            debug!("Ignoring message: {}", e);
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance: MessageAcceptance::Ignore,
+                received: None,
+            };
+        }
+        Ok(Err(e)) => {
+            warn!("Received invalid message: {}", e);
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance: MessageAcceptance::Reject,
+                received: None,
+            };
+        }
+        Err(e) => {
+            warn!("Message validation failed: {}", e);
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance: MessageAcceptance::Ignore,
+                received: None,
+            };
+        }
+    };
+
+    let (offer, assets, message_type, published_at) = match decoded {
+        Decoded::Offer {
+            offer,
+            assets,
+            message_type,
+            published_at,
+        } => (offer, assets, message_type, published_at),
+        Decoded::Taken {
+            offer_id,
+            coin_id,
+            spent_height,
+            published_at,
+        } => {
+            let acceptance = check_spent(coin_state, &coin_id).await;
+
+            let received = matches!(acceptance, MessageAcceptance::Accept).then(|| {
+                Received::Taken(TakenOffer {
+                    offer_id,
+                    coin_id,
+                    spent_height,
+                    message_id: Some(message_id.clone()),
+                    topic: Some(topic),
+                    propagation_source: Some(propagation_source),
+                    received_at,
+                    published_at,
+                })
+            });
+
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance,
+                received,
+            };
+        }
+        // Custom validators judge offers, a valid cancellation is always relayed
+        Decoded::Cancellation {
+            offer_id,
+            public_key,
+            published_at,
+        } => {
+            let received = ReceivedCancellation {
+                offer_id,
+                public_key,
+                message_id: message_id.clone(),
+                topic,
+                propagation_source,
+                received_at,
+                published_at,
+            };
+
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance: MessageAcceptance::Accept,
+                received: Some(Received::Cancellation(received)),
+            };
+        }
+    };
+
+    // Peers may know more kinds of assets than we do, don't penalize them for it
+    if let TopicKind::Asset(asset) = topic_kind {
+        if !assets.contains(&asset) {
+            debug!("Ignoring offer {} on topic of asset {}", offer.id(), asset);
+            return ValidationResult {
+                message_id,
+                propagation_source,
+                origin,
+                acceptance: MessageAcceptance::Ignore,
+                received: None,
+            };
+        }
+    }
+
+    let mut acceptance = MessageAcceptance::Accept;
+    for validator in validators {
+        acceptance = validator.validate(&offer, &propagation_source).await;
+        if !matches!(acceptance, MessageAcceptance::Accept) {
+            break;
+        }
+    }
+
+    let received = matches!(acceptance, MessageAcceptance::Accept).then(|| {
+        let received = ReceivedOffer {
+            offer_id: offer.id(),
+            compressed_offer: offer.compressed().clone(),
+            message_id: message_id.clone(),
+            topic,
+            propagation_source,
+            received_at,
+            expires_at: offer.expires_at(),
+            expires_at_height: offer.expires_at_height(),
+            assets,
+            message_type,
+            published_at,
+        };
+        Received::Offer(received, offer.coin_ids())
+    });
+
+    ValidationResult {
+        message_id,
+        propagation_source,
+        origin,
+        acceptance,
+        received,
+    }
+}
+
+enum Decoded {
+    Offer {
+        offer: Offer,
+        assets: Vec<Asset>,
+        message_type: Option<MessageType>,
+        published_at: Option<SystemTime>,
+    },
+    Cancellation {
+        offer_id: OfferId,
+        public_key: [u8; 48],
+        published_at: Option<SystemTime>,
+    },
+    Taken {
+        offer_id: OfferId,
+        coin_id: [u8; 32],
+        spent_height: u32,
+        published_at: Option<SystemTime>,
+    },
+}
+
+/// Notices are relayed if the coin is spent according to our coin state source. Without one, or
+/// while it's unreachable, they can't be checked and are dropped without penalty.
+async fn check_spent(
+    coin_state: Option<&dyn CoinStateSource>,
+    coin_id: &[u8; 32],
+) -> MessageAcceptance {
+    let Some(coin_state) = coin_state else {
+        debug!(
+            "Ignoring offer taken notice, no coin state source to check coin {}",
+            hex::encode(coin_id)
+        );
+        return MessageAcceptance::Ignore;
+    };
+
+    match coin_state.coin_records(&[*coin_id]).await {
+        Ok(records) if records.iter().any(|record| record.is_spent()) => MessageAcceptance::Accept,
+        // The notice may be ahead of our full node, don't penalize the peer
+        Ok(_) => {
+            debug!(
+                "Ignoring offer taken notice, coin {} isn't spent",
+                hex::encode(coin_id)
+            );
+            MessageAcceptance::Ignore
+        }
+        Err(e) => {
+            warn!("Coin state of {} unknown: {}", hex::encode(coin_id), e);
+            MessageAcceptance::Ignore
+        }
+    }
+}
+
+impl Decoded {
+    fn offer(
+        offer: Offer,
+        message_type: Option<MessageType>,
+        published_at: Option<SystemTime>,
+    ) -> Decoded {
+        Decoded::Offer {
+            assets: offer.assets(),
+            offer,
+            message_type,
+            published_at,
+        }
+    }
+}
+
+#[cfg(feature = "bls")]
+fn verify_cancellation(
+    payload: Bytes,
+    genesis_challenge: &[u8; 32],
+) -> Result<(OfferId, [u8; 48]), SplashError> {
+    let cancellation = Cancellation::from_bytes(payload)?;
+    let offer = signature::verify_cancellation(&cancellation, genesis_challenge)?;
+    Ok((offer.id(), cancellation.public_key))
+}
+
+// Cancellations can't be verified without BLS support, so they are not relayed
+#[cfg(not(feature = "bls"))]
+fn verify_cancellation(
+    _payload: Bytes,
+    _genesis_challenge: &[u8; 32],
+) -> Result<(OfferId, [u8; 48]), SplashError> {
+    Err(SplashError::UnsupportedMessageType(
+        MessageType::OfferCancellation.to_u8(),
+    ))
+}
+
+fn as_str(data: &[u8]) -> Result<&str, SplashError> {
+    std::str::from_utf8(data).map_err(|_| SplashError::InvalidMessageFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::full_node::CoinRecord;
+    use crate::{fixtures, TESTNET11_GENESIS_CHALLENGE};
+    use std::time::Duration;
+    use tokio::time::{self, Instant};
+
+    fn job(data: Vec<u8>) -> ValidationJob {
+        ValidationJob {
+            message_id: MessageId::from("message"),
+            propagation_source: PeerId::random(),
+            topic: TopicHash::from_raw("/splash/messages/2"),
+            topic_kind: TopicKind::Offers,
+            origin: Origin::Gossip,
+            data: data.into(),
+            received_at: SystemTime::now(),
+        }
+    }
+
+    async fn acceptance(data: Vec<u8>) -> MessageAcceptance {
+        validate(job(data), &[], TESTNET11_GENESIS_CHALLENGE, None)
+            .await
+            .acceptance
+    }
+
+    /// Records the validators called, in order, and returns `acceptance`
+    struct StubValidator {
+        name: &'static str,
+        acceptance: MessageAcceptance,
+        calls: Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl MessageValidator for StubValidator {
+        async fn validate(
+            &self,
+            _offer: &Offer,
+            _propagation_source: &PeerId,
+        ) -> MessageAcceptance {
+            self.calls.lock().unwrap().push(self.name);
+            copy(&self.acceptance)
+        }
+
+        async fn validate_application_message(
+            &self,
+            _topic: &TopicHash,
+            _data: &[u8],
+            _propagation_source: &PeerId,
+        ) -> MessageAcceptance {
+            self.calls.lock().unwrap().push(self.name);
+            copy(&self.acceptance)
+        }
+    }
+
+    // `MessageAcceptance` isn't `Clone`
+    fn copy(acceptance: &MessageAcceptance) -> MessageAcceptance {
+        match acceptance {
+            MessageAcceptance::Accept => MessageAcceptance::Accept,
+            MessageAcceptance::Reject => MessageAcceptance::Reject,
+            MessageAcceptance::Ignore => MessageAcceptance::Ignore,
+        }
+    }
+
+    /// Validates `job` with a stub validator for every acceptance, returns the result and the
+    /// validators called
+    async fn validate_with(
+        job: ValidationJob,
+        acceptances: &[MessageAcceptance],
+    ) -> (ValidationResult, Vec<&'static str>) {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let validators: Vec<Arc<dyn MessageValidator>> = ["first", "second", "third"]
+            .into_iter()
+            .zip(acceptances)
+            .map(|(name, acceptance)| {
+                Arc::new(StubValidator {
+                    name,
+                    acceptance: copy(acceptance),
+                    calls: calls.clone(),
+                }) as Arc<dyn MessageValidator>
+            })
+            .collect();
+
+        let result = validate(job, &validators, TESTNET11_GENESIS_CHALLENGE, None).await;
+        let calls = calls.lock().unwrap().clone();
+        (result, calls)
+    }
+
+    fn application_job(data: &[u8]) -> ValidationJob {
+        ValidationJob {
+            topic: TopicHash::from_raw("/splash-test/app/1"),
+            topic_kind: TopicKind::Application,
+            ..job(data.to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_validators_in_registration_order() {
+        let offer = fixtures::XCH_FOR_CAT.as_bytes().to_vec();
+        let (result, calls) = validate_with(
+            job(offer),
+            &[MessageAcceptance::Accept, MessageAcceptance::Accept],
+        )
+        .await;
+
+        assert_eq!(calls, ["first", "second"]);
+        assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+        let Some(Received::Offer(received, _)) = result.received else {
+            panic!("expected an offer");
+        };
+        assert_eq!(
+            received.offer_id,
+            Offer::decode(fixtures::XCH_FOR_CAT).unwrap().id()
+        );
+    }
+
+    #[tokio::test]
+    async fn first_validator_not_accepting_decides() {
+        let offer = fixtures::XCH_FOR_CAT.as_bytes().to_vec();
+
+        let (result, calls) = validate_with(
+            job(offer.clone()),
+            &[
+                MessageAcceptance::Accept,
+                MessageAcceptance::Ignore,
+                MessageAcceptance::Reject,
+            ],
+        )
+        .await;
+        assert_eq!(calls, ["first", "second"]);
+        assert!(matches!(result.acceptance, MessageAcceptance::Ignore));
+        assert!(result.received.is_none());
+
+        let (result, calls) = validate_with(
+            job(offer),
+            &[MessageAcceptance::Reject, MessageAcceptance::Ignore],
+        )
+        .await;
+        assert_eq!(calls, ["first"]);
+        assert!(matches!(result.acceptance, MessageAcceptance::Reject));
+        assert!(result.received.is_none());
+    }
+
+    #[tokio::test]
+    async fn validators_only_see_valid_offers() {
+        let (result, calls) =
+            validate_with(job(b"offer1invalid".to_vec()), &[MessageAcceptance::Accept]).await;
+
+        assert!(calls.is_empty());
+        assert!(matches!(result.acceptance, MessageAcceptance::Reject));
+    }
+
+    #[tokio::test]
+    async fn validates_application_messages() {
+        let (result, calls) = validate_with(
+            application_job(b"hello"),
+            &[MessageAcceptance::Accept, MessageAcceptance::Accept],
+        )
+        .await;
+        assert_eq!(calls, ["first", "second"]);
+        assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+        let Some(Received::Message(message)) = result.received else {
+            panic!("expected an application message");
+        };
+        assert_eq!(message.topic, TopicHash::from_raw("/splash-test/app/1"));
+        assert_eq!(message.data, b"hello"[..]);
+
+        // Application messages aren't parsed as offers
+        let (result, _) = validate_with(application_job(b"hello"), &[]).await;
+        assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+
+        for acceptance in [MessageAcceptance::Ignore, MessageAcceptance::Reject] {
+            let (result, calls) = validate_with(
+                application_job(b"hello"),
+                &[copy(&acceptance), MessageAcceptance::Accept],
+            )
+            .await;
+            assert_eq!(calls, ["first"]);
+            assert_eq!(
+                std::mem::discriminant(&result.acceptance),
+                std::mem::discriminant(&acceptance)
+            );
+            assert!(result.received.is_none());
+        }
+    }
+
+    const DELAY: Duration = Duration::from_secs(1);
+
+    /// Accepts application messages after `DELAY`
+    struct SlowValidator;
+
+    #[async_trait]
+    impl MessageValidator for SlowValidator {
+        async fn validate(
+            &self,
+            _offer: &Offer,
+            _propagation_source: &PeerId,
+        ) -> MessageAcceptance {
+            MessageAcceptance::Accept
+        }
+
+        async fn validate_application_message(
+            &self,
+            _topic: &TopicHash,
+            _data: &[u8],
+            _propagation_source: &PeerId,
+        ) -> MessageAcceptance {
+            time::sleep(DELAY).await;
+            MessageAcceptance::Accept
+        }
+    }
+
+    fn slow_workers(
+        workers: usize,
+        queue_size: usize,
+    ) -> (
+        mpsc::Sender<ValidationJob>,
+        mpsc::Receiver<ValidationResult>,
+    ) {
+        spawn_validation_workers(
+            workers,
+            queue_size,
+            vec![Arc::new(SlowValidator)],
+            TESTNET11_GENESIS_CHALLENGE,
+            None,
+        )
+    }
+
+    fn numbered_job(n: usize) -> ValidationJob {
+        ValidationJob {
+            message_id: MessageId::from(n.to_string()),
+            ..application_job(b"hello")
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_messages_while_the_queue_is_full() {
+        let (queue, mut results) = slow_workers(1, 1);
+
+        // The worker validates the first message, the second waits in the queue
+        assert!(enqueue(&queue, numbered_job(1)).is_none());
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(enqueue(&queue, numbered_job(2)).is_none());
+
+        let Some(ignored) = enqueue(&queue, numbered_job(3)) else {
+            panic!("expected the queue to be full");
+        };
+        assert_eq!(ignored.message_id, MessageId::from("3"));
+        assert!(matches!(ignored.acceptance, MessageAcceptance::Ignore));
+        assert!(ignored.received.is_none());
+
+        for n in [1, 2] {
+            let result = results.recv().await.unwrap();
+            assert_eq!(result.message_id, MessageId::from(n.to_string()));
+            assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validates_messages_concurrently() {
+        let (queue, mut results) = slow_workers(4, 4);
+        let started = Instant::now();
+
+        for n in 0..4 {
+            assert!(enqueue(&queue, numbered_job(n)).is_none());
+        }
+        for _ in 0..4 {
+            let result = results.recv().await.unwrap();
+            assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+        }
+
+        assert!(started.elapsed() >= DELAY);
+        assert!(started.elapsed() < 2 * DELAY);
+    }
+
+    /// Coin states of a single coin, `None` if the RPC fails
+    struct MockCoinState(Option<Vec<CoinRecord>>);
+
+    #[async_trait]
+    impl CoinStateSource for MockCoinState {
+        async fn coin_records(
+            &self,
+            _coin_ids: &[[u8; 32]],
+        ) -> Result<Vec<CoinRecord>, SplashError> {
+            self.0
+                .clone()
+                .ok_or_else(|| SplashError::FullNodeRpc("unreachable".to_string()))
+        }
+    }
+
+    fn coin_record(offer: &Offer, spent_block_index: u32) -> CoinRecord {
+        CoinRecord {
+            coin: offer.spend_bundle().coin_spends[0].coin.clone(),
+            confirmed_block_index: 1,
+            spent_block_index,
+            coinbase: false,
+            timestamp: 0,
+        }
+    }
+
+    async fn validate_notice(coin_state: Option<&dyn CoinStateSource>) -> ValidationResult {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let notice = TakenNotice {
+            offer: offer.compressed().clone(),
+            coin_id: offer.coin_ids()[0],
+            spent_height: 10,
+        };
+        let data = Envelope::offer_taken(&notice).to_bytes();
+
+        validate(job(data), &[], TESTNET11_GENESIS_CHALLENGE, coin_state).await
+    }
+
+    #[tokio::test]
+    async fn relays_notices_of_spent_coins() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let coin_state = MockCoinState(Some(vec![coin_record(&offer, 10)]));
+
+        let result = validate_notice(Some(&coin_state)).await;
+
+        assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+        let Some(Received::Taken(taken)) = result.received else {
+            panic!("expected a taken offer");
+        };
+        assert_eq!(taken.offer_id, offer.id());
+        assert_eq!(taken.spent_height, 10);
+    }
+
+    #[tokio::test]
+    async fn ignores_notices_that_cant_be_checked() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let unspent = MockCoinState(Some(vec![coin_record(&offer, 0)]));
+        let unknown = MockCoinState(Some(Vec::new()));
+        let failing = MockCoinState(None);
+
+        for coin_state in [
+            Some(&unspent as &dyn CoinStateSource),
+            Some(&unknown),
+            Some(&failing),
+            None,
+        ] {
+            let result = validate_notice(coin_state).await;
+
+            assert!(matches!(result.acceptance, MessageAcceptance::Ignore));
+            assert!(result.received.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_notices_for_foreign_coins() {
+        let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+        let notice = TakenNotice {
+            offer: offer.compressed().clone(),
+            coin_id: [1; 32],
+            spent_height: 10,
+        };
+        let coin_state = MockCoinState(Some(vec![coin_record(&offer, 10)]));
+
+        let result = validate(
+            job(Envelope::offer_taken(&notice).to_bytes()),
+            &[],
+            TESTNET11_GENESIS_CHALLENGE,
+            Some(&coin_state),
+        )
+        .await;
+
+        assert!(matches!(result.acceptance, MessageAcceptance::Reject));
+    }
+
+    #[cfg(feature = "bls")]
+    mod cancellations {
+        use super::*;
+        use hex_literal::hex;
+
+        // Secret key of the maker of `fixtures::XCH_FOR_CAT`
+        const MAKER_KEY: [u8; 32] =
+            hex!("6dcb713e28c07d548672f4bc0aee46a5a4c81d6f31498db6ceb8110107aaacda");
+
+        fn cancellation(genesis_challenge: &[u8; 32]) -> Cancellation {
+            let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+            signature::sign_cancellation(&offer, &MAKER_KEY, genesis_challenge).unwrap()
+        }
+
+        #[tokio::test]
+        async fn accepts_signed_cancellations() {
+            let data = Envelope::cancellation(&cancellation(&TESTNET11_GENESIS_CHALLENGE));
+            let result =
+                validate(job(data.to_bytes()), &[], TESTNET11_GENESIS_CHALLENGE, None).await;
+
+            assert!(matches!(result.acceptance, MessageAcceptance::Accept));
+            let Some(Received::Cancellation(received)) = result.received else {
+                panic!("expected a cancellation");
+            };
+            assert_eq!(
+                received.offer_id,
+                Offer::decode(fixtures::XCH_FOR_CAT).unwrap().id()
+            );
+        }
+
+        #[tokio::test]
+        async fn rejects_cancellations_with_bad_signatures() {
+            // Signed for another network
+            let data = Envelope::cancellation(&cancellation(&crate::MAINNET_GENESIS_CHALLENGE));
+            assert!(matches!(
+                acceptance(data.to_bytes()).await,
+                MessageAcceptance::Reject
+            ));
+
+            // Signed for another offer
+            let mut other = cancellation(&TESTNET11_GENESIS_CHALLENGE);
+            other.offer = Offer::decode(fixtures::EXPIRING)
+                .unwrap()
+                .compressed()
+                .clone();
+            assert!(matches!(
+                acceptance(Envelope::cancellation(&other).to_bytes()).await,
+                MessageAcceptance::Reject
+            ));
+        }
+
+        #[test]
+        fn only_the_maker_can_cancel() {
+            let offer = Offer::decode(fixtures::XCH_FOR_CAT).unwrap();
+
+            assert!(matches!(
+                signature::sign_cancellation(&offer, &[1; 32], &TESTNET11_GENESIS_CHALLENGE),
+                Err(SplashError::InvalidCancellation(_))
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "bls"))]
+    #[tokio::test]
+    async fn ignores_cancellations() {
+        let cancellation = crate::Cancellation {
+            offer: Offer::decode(fixtures::XCH_FOR_CAT)
+                .unwrap()
+                .compressed()
+                .clone(),
+            public_key: [0; 48],
+            signature: [0; 96],
+        };
+
+        assert!(matches!(
+            acceptance(Envelope::cancellation(&cancellation).to_bytes()).await,
+            MessageAcceptance::Ignore
+        ));
+    }
+}
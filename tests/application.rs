@@ -0,0 +1,61 @@
+//! Nodes exchange messages on application topics while they are subscribed to them
+
+mod common;
+
+use common::{listening, start, UNREACHABLE_PEER};
+use splash::SplashEvent;
+use std::time::Duration;
+use tokio::time;
+
+const TOPIC: &str = "/splash-test/application/1";
+
+#[tokio::test]
+async fn stops_delivering_messages_after_unsubscribing() {
+    let (receiving, mut events, address) =
+        start(listening().with_known_peers(vec![UNREACHABLE_PEER.parse().unwrap()])).await;
+    assert!(receiving.subscribe(TOPIC).await.unwrap());
+    assert!(!receiving.subscribe(TOPIC).await.unwrap());
+
+    let (publishing, _events, _) = start(listening().with_known_peers(vec![address])).await;
+
+    time::timeout(Duration::from_secs(20), async {
+        // Publishing fails until the subscription of the receiving node arrived
+        while publishing.publish(TOPIC, "subscribed").await.is_err() {
+            time::sleep(Duration::from_millis(200)).await;
+        }
+
+        loop {
+            if let SplashEvent::ApplicationMessage(message) = events.recv().await.unwrap() {
+                assert_eq!(message.data, "subscribed");
+                break;
+            }
+        }
+    })
+    .await
+    .expect("message was delivered");
+
+    assert!(receiving.unsubscribe(TOPIC).await.unwrap());
+    assert!(!receiving.unsubscribe(TOPIC).await.unwrap());
+
+    // Publishing fails once the publishing node learned about the unsubscription
+    for n in 0..10 {
+        publishing
+            .publish(TOPIC, format!("unsubscribed {}", n))
+            .await
+            .ok();
+        time::sleep(Duration::from_millis(200)).await;
+    }
+
+    let delivered = time::timeout(Duration::from_secs(2), async {
+        loop {
+            if let SplashEvent::ApplicationMessage(message) = events.recv().await.unwrap() {
+                break message;
+            }
+        }
+    })
+    .await;
+    assert!(
+        delivered.is_err(),
+        "message was delivered after unsubscribing"
+    );
+}
@@ -0,0 +1,80 @@
+//! Nodes publishing with different authorship modes still receive each other's messages
+
+mod common;
+
+use common::{listening, start, UNREACHABLE_PEER};
+use libp2p::Multiaddr;
+use splash::{Authorship, Splash, SplashEvent};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+const TOPIC: &str = "/splash-test/authorship/1";
+
+async fn start_node(
+    authorship: Authorship,
+    known_peer: Multiaddr,
+) -> (Splash, mpsc::Receiver<SplashEvent>, Multiaddr) {
+    start(
+        listening()
+            .with_known_peers(vec![known_peer])
+            .with_authorship(authorship),
+    )
+    .await
+}
+
+async fn assert_delivered(publisher: Authorship, receiver: Authorship) {
+    let (receiving, mut events, address) =
+        start_node(receiver, UNREACHABLE_PEER.parse().unwrap()).await;
+    receiving.subscribe(TOPIC).await.unwrap();
+
+    let (publishing, _events, _) = start_node(publisher, address).await;
+
+    let data = format!("{:?} to {:?}", publisher, receiver).into_bytes();
+    time::timeout(Duration::from_secs(20), async {
+        // Publishing fails until the subscription of the receiving node arrived
+        while publishing.publish(TOPIC, data.clone()).await.is_err() {
+            time::sleep(Duration::from_millis(200)).await;
+        }
+
+        loop {
+            if let SplashEvent::ApplicationMessage(message) = events.recv().await.unwrap() {
+                if message.data == data {
+                    break;
+                }
+            }
+        }
+    })
+    .await
+    .expect("message was delivered");
+}
+
+#[tokio::test]
+async fn signed_to_transitional() {
+    assert_delivered(Authorship::Signed, Authorship::Transitional).await;
+}
+
+#[tokio::test]
+async fn signed_to_anonymous() {
+    assert_delivered(Authorship::Signed, Authorship::Anonymous).await;
+}
+
+#[tokio::test]
+async fn transitional_to_signed() {
+    assert_delivered(Authorship::Transitional, Authorship::Signed).await;
+}
+
+#[tokio::test]
+async fn transitional_to_anonymous() {
+    assert_delivered(Authorship::Transitional, Authorship::Anonymous).await;
+}
+
+#[tokio::test]
+async fn anonymous_to_signed() {
+    assert_delivered(Authorship::Anonymous, Authorship::Signed).await;
+}
+
+#[tokio::test]
+async fn anonymous_to_transitional() {
+    assert_delivered(Authorship::Anonymous, Authorship::Transitional).await;
+}
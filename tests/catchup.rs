@@ -0,0 +1,57 @@
+//! Nodes catch up on the offers their peers received recently
+
+mod common;
+
+use common::{listening, start, OFFERS, UNREACHABLE_PEER};
+use splash::SplashEvent;
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn catches_up_on_more_offers_than_the_validation_queue_holds() {
+    let (publishing, mut publishing_events, address) = start(
+        listening()
+            .with_testnet()
+            .with_known_peers(vec![UNREACHABLE_PEER.parse().unwrap()])
+            .with_catchup(Duration::from_secs(600))
+            .with_sync_interval(None),
+    )
+    .await;
+
+    // Without peers the offers are fluffed right away, publishing them fails but they are kept
+    // for peers catching up
+    for offer in OFFERS {
+        publishing
+            .broadcast_message(offer.trim_end())
+            .await
+            .unwrap();
+    }
+    let mut failed = 0;
+    while failed < OFFERS.len() {
+        if let SplashEvent::MessageBroadcastFailed(_) = publishing_events.recv().await.unwrap() {
+            failed += 1;
+        }
+    }
+
+    let (_catching_up, mut events, _) = start(
+        listening()
+            .with_testnet()
+            .with_known_peers(vec![address])
+            .with_catchup(Duration::from_secs(600))
+            .with_sync_interval(None)
+            .with_validation_queue_size(2),
+    )
+    .await;
+
+    let mut received = HashSet::new();
+    time::timeout(Duration::from_secs(20), async {
+        while received.len() < OFFERS.len() {
+            if let SplashEvent::MessageReceived(offer) = events.recv().await.unwrap() {
+                received.insert(offer.offer_id);
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("caught up on {} of {} offers", received.len(), OFFERS.len()));
+}
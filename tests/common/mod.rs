@@ -0,0 +1,149 @@
+//! Fixtures shared by the integration tests
+
+// Every test crate only uses some of them
+#![allow(dead_code)]
+
+use futures::StreamExt;
+use libp2p::gossipsub::{self, IdentTopic, MessageId, ValidationMode};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{noise, tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder};
+use splash::{Splash, SplashContext, SplashEvent};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+// Nothing listens there, so nodes knowing it don't resolve peers from DNS
+pub const UNREACHABLE_PEER: &str =
+    "/ip4/127.0.0.1/tcp/1/p2p/12D3KooWPjceQrSwdWXPyLLeABRXmuqt69Rg3sBYbU1Nft9HyQ6X";
+
+// Offers signed for testnet11
+pub const OFFERS: [&str; 6] = [
+    include_str!("../fixtures/xch_for_cat.offer"),
+    include_str!("../fixtures/cat_for_xch.offer"),
+    include_str!("../fixtures/cat_for_cat.offer"),
+    include_str!("../fixtures/xch_for_nft.offer"),
+    include_str!("../fixtures/nft_for_xch.offer"),
+    include_str!("../fixtures/expiring.offer"),
+];
+
+/// A node listening on a random local TCP port
+pub fn listening() -> Splash {
+    Splash::new().with_listen_addresses(vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()])
+}
+
+/// Starts a node, returns it along with the first address it listens on
+pub async fn start(node: Splash) -> (Splash, mpsc::Receiver<SplashEvent>, Multiaddr) {
+    let SplashContext { node, mut events } = node.build().await.unwrap();
+
+    let mut peer_id = None;
+    let address = loop {
+        match events.recv().await.unwrap() {
+            SplashEvent::Initialized(id) => peer_id = Some(id),
+            SplashEvent::NewListenAddress(address) => {
+                break address.with(Protocol::P2p(peer_id.unwrap()));
+            }
+            _ => {}
+        }
+    };
+
+    (node, events, address)
+}
+
+/// Waits for a node to connect to `peer_id`
+pub async fn connected(events: &mut mpsc::Receiver<SplashEvent>, peer_id: PeerId) {
+    time::timeout(Duration::from_secs(20), async {
+        loop {
+            if let SplashEvent::PeerConnected(connected) = events.recv().await.unwrap() {
+                if connected == peer_id {
+                    break;
+                }
+            }
+        }
+    })
+    .await
+    .expect("connected");
+}
+
+/// Starts a bare gossipsub peer on a random local TCP port subscribed to `topics`, returns it along
+/// with its address. It doesn't support any other Splash protocol.
+pub async fn start_peer(topics: &[&str]) -> (Swarm<gossipsub::Behaviour>, Multiaddr) {
+    let mut peer = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .unwrap()
+        .with_behaviour(|key| {
+            let config = gossipsub::ConfigBuilder::default()
+                .message_id_fn(|message| {
+                    let mut s = DefaultHasher::new();
+                    message.data.hash(&mut s);
+                    MessageId::from(s.finish().to_string())
+                })
+                .validation_mode(ValidationMode::Permissive)
+                .build()
+                .unwrap();
+            gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), config)
+                .unwrap()
+        })
+        .unwrap()
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+
+    for topic in topics {
+        peer.behaviour_mut()
+            .subscribe(&IdentTopic::new(*topic))
+            .unwrap();
+    }
+    peer.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+
+    let address = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = peer.select_next_some().await {
+            break address.with(Protocol::P2p(*peer.local_peer_id()));
+        }
+    };
+
+    (peer, address)
+}
+
+/// Waits up to `timeout` for the next message of a bare gossipsub peer
+pub async fn receive(
+    peer: &mut Swarm<gossipsub::Behaviour>,
+    timeout: Duration,
+) -> Option<gossipsub::Message> {
+    time::timeout(timeout, async {
+        loop {
+            if let SwarmEvent::Behaviour(gossipsub::Event::Message { message, .. }) =
+                peer.select_next_some().await
+            {
+                break message;
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+/// Broadcasts offers until `peer` receives one, returns the offer and the message. Publishing fails
+/// until the node learned about the subscriptions of its peers.
+pub async fn broadcast_until_received(
+    node: &Splash,
+    peer: &mut Swarm<gossipsub::Behaviour>,
+) -> (&'static str, gossipsub::Message) {
+    for offer in OFFERS {
+        let offer = offer.trim_end();
+        node.broadcast_message(offer).await.unwrap();
+
+        if let Some(message) = receive(peer, Duration::from_secs(2)).await {
+            return (offer, message);
+        }
+    }
+
+    panic!("no offer received");
+}
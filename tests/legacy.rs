@@ -0,0 +1,58 @@
+//! Nodes keep legacy nodes, which only understand bare offer strings, supplied with offers
+
+mod common;
+
+use common::{broadcast_until_received, listening, receive, start, start_peer, UNREACHABLE_PEER};
+use libp2p::gossipsub::TopicHash;
+use libp2p::Multiaddr;
+use splash::{Splash, SplashEvent};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const TOPIC: &str = "/splash-testnet/messages/2";
+const LEGACY_TOPIC: &str = "/splash-testnet/messages/1";
+
+/// Starts a testnet node gossiping offers right away
+async fn start_node(known_peer: Multiaddr) -> (Splash, mpsc::Receiver<SplashEvent>, Multiaddr) {
+    start(
+        listening()
+            .with_testnet()
+            .with_known_peers(vec![known_peer])
+            .with_stem_phase(false),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn publishes_offers_for_legacy_peers() {
+    let (mut legacy, address) = start_peer(&[LEGACY_TOPIC]).await;
+    let (node, _events, _) = start_node(address).await;
+
+    let (offer, message) = broadcast_until_received(&node, &mut legacy).await;
+    assert_eq!(message.topic, TopicHash::from_raw(LEGACY_TOPIC));
+    assert_eq!(message.data, offer.as_bytes());
+}
+
+#[tokio::test]
+async fn publishes_offers_for_updated_peers_on_the_enveloped_topic_only() {
+    let (mut updated, address) = start_peer(&[TOPIC, LEGACY_TOPIC]).await;
+    let (node, _events, _) = start_node(address).await;
+
+    let (_, message) = broadcast_until_received(&node, &mut updated).await;
+    assert_eq!(message.topic, TopicHash::from_raw(TOPIC));
+    assert_eq!(receive(&mut updated, Duration::from_secs(2)).await, None);
+}
+
+#[tokio::test]
+async fn bridges_offers_to_legacy_peers() {
+    let (publishing, _events, address) = start_node(UNREACHABLE_PEER.parse().unwrap()).await;
+    let (_bridging, _events, address) = start_node(address).await;
+
+    // Only connected to the bridging node, which receives the offers on the enveloped topic
+    let (mut legacy, _) = start_peer(&[LEGACY_TOPIC]).await;
+    legacy.dial(address).unwrap();
+
+    let (offer, message) = broadcast_until_received(&publishing, &mut legacy).await;
+    assert_eq!(message.topic, TopicHash::from_raw(LEGACY_TOPIC));
+    assert_eq!(message.data, offer.as_bytes());
+}
@@ -0,0 +1,140 @@
+//! Nodes behind a SOCKS5 proxy dial peers through it, including `/onion3` addresses
+
+mod common;
+
+use common::{connected, listening, start, UNREACHABLE_PEER};
+use libp2p::multiaddr::Protocol;
+use libp2p::PeerId;
+use splash::{Splash, SplashContext, SplashEvent};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+const ONION: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+/// A SOCKS5 proxy connecting every request to `upstream`, reports the requested targets
+async fn start_proxy(upstream: SocketAddr) -> (SocketAddr, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (targets_tx, targets) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let targets_tx = targets_tx.clone();
+
+            tokio::spawn(async move {
+                let mut greeting = [0; 2];
+                client.read_exact(&mut greeting).await.unwrap();
+                let mut methods = vec![0; greeting[1] as usize];
+                client.read_exact(&mut methods).await.unwrap();
+                client.write_all(&[5, 0]).await.unwrap();
+
+                let mut request = [0; 4];
+                client.read_exact(&mut request).await.unwrap();
+                let host = match request[3] {
+                    1 => {
+                        let mut ip = [0; 4];
+                        client.read_exact(&mut ip).await.unwrap();
+                        std::net::Ipv4Addr::from(ip).to_string()
+                    }
+                    3 => {
+                        let mut host = vec![0; client.read_u8().await.unwrap() as usize];
+                        client.read_exact(&mut host).await.unwrap();
+                        String::from_utf8(host).unwrap()
+                    }
+                    atyp => panic!("unexpected address type {}", atyp),
+                };
+                let port = client.read_u16().await.unwrap();
+                targets_tx.send(format!("{}:{}", host, port)).await.ok();
+
+                let mut upstream = TcpStream::connect(upstream).await.unwrap();
+                client
+                    .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                tokio::io::copy_bidirectional(&mut client, &mut upstream)
+                    .await
+                    .ok();
+            });
+        }
+    });
+
+    (address, targets)
+}
+
+/// Starts a node listening on a random local port, returns its peer ID and socket address
+async fn start_peer() -> (Splash, mpsc::Receiver<SplashEvent>, PeerId, SocketAddr) {
+    let (node, events, address) =
+        start(listening().with_known_peers(vec![UNREACHABLE_PEER.parse().unwrap()])).await;
+
+    let mut peer_id = None;
+    let mut port = None;
+    for protocol in address.iter() {
+        match protocol {
+            Protocol::Tcp(tcp_port) => port = Some(tcp_port),
+            Protocol::P2p(id) => peer_id = Some(id),
+            _ => {}
+        }
+    }
+
+    (
+        node,
+        events,
+        peer_id.unwrap(),
+        ([127, 0, 0, 1], port.unwrap()).into(),
+    )
+}
+
+/// Connects to a peer through the proxy, `address` is the peer's address for the peer's port and
+/// `target` what the proxy is asked to connect to
+async fn assert_dialed_through_proxy(
+    address: impl Fn(u16) -> String,
+    target: impl Fn(u16) -> String,
+) {
+    let (_peer, _peer_events, peer_id, peer_address) = start_peer().await;
+    let (proxy, mut targets) = start_proxy(peer_address).await;
+
+    let known_peer = format!("{}/p2p/{}", address(peer_address.port()), peer_id);
+    let SplashContext {
+        node: _node,
+        mut events,
+    } = Splash::new()
+        .with_known_peers(vec![known_peer.parse().unwrap()])
+        .with_socks5_proxy(proxy)
+        .build()
+        .await
+        .unwrap();
+
+    connected(&mut events, peer_id).await;
+
+    assert_eq!(targets.recv().await.unwrap(), target(peer_address.port()));
+}
+
+#[tokio::test]
+async fn dials_ip_addresses() {
+    assert_dialed_through_proxy(
+        |port| format!("/ip4/127.0.0.1/tcp/{}", port),
+        |port| format!("127.0.0.1:{}", port),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn dials_host_names() {
+    assert_dialed_through_proxy(
+        |port| format!("/dns4/peer.example/tcp/{}", port),
+        |port| format!("peer.example:{}", port),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn dials_onion_addresses() {
+    assert_dialed_through_proxy(
+        |port| format!("/onion3/{}:{}", ONION, port),
+        |port| format!("{}.onion:{}", ONION, port),
+    )
+    .await;
+}
@@ -0,0 +1,68 @@
+//! Offers travel the stem before they are gossiped, and are gossiped when the stem breaks
+
+mod common;
+
+use common::{
+    broadcast_until_received, connected, listening, start, start_peer, OFFERS, UNREACHABLE_PEER,
+};
+use futures::StreamExt;
+use libp2p::gossipsub::TopicHash;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use splash::{Splash, SplashEvent};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+
+const TOPIC: &str = "/splash-testnet/messages/2";
+
+async fn start_node(known_peer: Multiaddr) -> (Splash, mpsc::Receiver<SplashEvent>, Multiaddr) {
+    start(
+        listening()
+            .with_testnet()
+            .with_known_peers(vec![known_peer]),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn fluffs_offers_at_the_end_of_the_stem() {
+    let (_fluffing, mut fluffing_events, address) =
+        start_node(UNREACHABLE_PEER.parse().unwrap()).await;
+    let (publishing, mut events, _) = start_node(address.clone()).await;
+    let Some(Protocol::P2p(peer_id)) = address.iter().last() else {
+        unreachable!()
+    };
+    connected(&mut events, peer_id).await;
+
+    // The only peer of the publishing node is its successor, which can't relay it back
+    let offer = OFFERS[0].trim_end();
+    publishing.broadcast_message(offer).await.unwrap();
+
+    time::timeout(Duration::from_secs(20), async {
+        loop {
+            if let SplashEvent::MessageReceived(received) = fluffing_events.recv().await.unwrap() {
+                assert_eq!(received.to_bech32(), offer);
+                break;
+            }
+        }
+    })
+    .await
+    .expect("offer was relayed");
+}
+
+#[tokio::test]
+async fn fluffs_offers_when_the_stem_breaks() {
+    let (mut peer, address) = start_peer(&[TOPIC]).await;
+    let peer_id = *peer.local_peer_id();
+    let (publishing, mut events, _) = start_node(address).await;
+    // The peer takes part in the handshake while it's polled
+    tokio::select! {
+        _ = connected(&mut events, peer_id) => {},
+        _ = async { loop { peer.select_next_some().await; } } => {},
+    }
+
+    // Relaying to the peer fails as it doesn't support stems, so the offer is gossiped
+    let (_, message) = broadcast_until_received(&publishing, &mut peer).await;
+    assert_eq!(message.topic, TopicHash::from_raw(TOPIC));
+}
@@ -0,0 +1,123 @@
+//! Nodes connect over QUIC, TCP and WebSocket alike
+
+mod common;
+
+use common::{connected, listening, start, UNREACHABLE_PEER};
+use futures::StreamExt;
+use libp2p::core::ConnectedPoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{dummy, SwarmEvent};
+use libp2p::{noise, tcp, yamux, SwarmBuilder};
+use splash::{Splash, SplashContext};
+use std::time::Duration;
+use tokio::time;
+
+/// Starts a node listening on `listen_address` and connects to it from a node using the default
+/// transports
+async fn assert_connects(listen_address: &str) {
+    let (_listening, _events, address) = start(
+        Splash::new()
+            .with_listen_addresses(vec![listen_address.parse().unwrap()])
+            .with_known_peers(vec![UNREACHABLE_PEER.parse().unwrap()]),
+    )
+    .await;
+    let Some(Protocol::P2p(peer_id)) = address.iter().last() else {
+        unreachable!()
+    };
+
+    let (_dialing, mut events, _) = start(listening().with_known_peers(vec![address])).await;
+
+    connected(&mut events, peer_id).await;
+}
+
+#[tokio::test]
+async fn connects_over_quic() {
+    assert_connects("/ip4/127.0.0.1/udp/0/quic-v1").await;
+}
+
+#[tokio::test]
+async fn connects_over_tcp() {
+    assert_connects("/ip4/127.0.0.1/tcp/0").await;
+}
+
+#[tokio::test]
+async fn connects_over_websocket() {
+    assert_connects("/ip4/127.0.0.1/tcp/0/ws").await;
+}
+
+#[tokio::test]
+async fn refuses_to_listen_on_secure_websocket() {
+    let result = Splash::new()
+        .with_listen_addresses(vec!["/ip4/127.0.0.1/tcp/0/wss".parse().unwrap()])
+        .with_known_peers(vec![UNREACHABLE_PEER.parse().unwrap()])
+        .build()
+        .await;
+
+    let error = result.err().expect("refused").to_string();
+    assert!(error.contains("TLS terminating proxy"), "{}", error);
+}
+
+#[tokio::test]
+async fn prefers_quic_over_tcp() {
+    let mut peer = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .unwrap()
+        .with_quic()
+        .with_behaviour(|_| dummy::Behaviour)
+        .unwrap()
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    peer.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    peer.listen_on("/ip4/127.0.0.1/udp/0/quic-v1".parse().unwrap())
+        .unwrap();
+
+    let mut addresses = Vec::new();
+    while addresses.len() < 2 {
+        if let SwarmEvent::NewListenAddr { address, .. } = peer.select_next_some().await {
+            addresses.push(address.with(Protocol::P2p(*peer.local_peer_id())));
+        }
+    }
+    // Known in the order the transports are least preferred
+    addresses.sort_by_key(|address| address.iter().any(|p| p == Protocol::QuicV1));
+
+    let SplashContext { node: _dialing, .. } = Splash::new()
+        .with_listen_addresses(vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()])
+        .with_known_peers(addresses)
+        .build()
+        .await
+        .unwrap();
+
+    let local_addr = time::timeout(Duration::from_secs(20), async {
+        loop {
+            if let SwarmEvent::ConnectionEstablished {
+                endpoint: ConnectedPoint::Listener { local_addr, .. },
+                ..
+            } = peer.select_next_some().await
+            {
+                break local_addr;
+            }
+        }
+    })
+    .await
+    .expect("connected");
+    assert!(local_addr.iter().any(|p| p == Protocol::QuicV1));
+
+    // TCP is not dialed while QUIC works
+    let established = time::timeout(Duration::from_secs(2), async {
+        loop {
+            if let SwarmEvent::ConnectionEstablished { endpoint, .. } =
+                peer.select_next_some().await
+            {
+                break endpoint;
+            }
+        }
+    })
+    .await;
+    assert!(established.is_err(), "also connected: {:?}", established);
+}